@@ -0,0 +1,193 @@
+//! Audio Processing Unit, see: https://www.nesdev.org/wiki/APU
+//! The channels are clocked by the frame sequencer at quarter and half frames, and mixed into one output level.
+
+mod units;
+mod pulse;
+mod triangle;
+mod noise;
+mod dmc;
+
+use crate::region::Region;
+use pulse::Pulse;
+use triangle::Triangle;
+use noise::Noise;
+use dmc::Dmc;
+
+#[derive(Debug, Clone)]
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    /// CPU cycles since the frame sequencer was last reset
+    frame_cycle: u32,
+    /// 5-step sequence, which never raises the frame IRQ
+    five_step: bool,
+    /// Frame IRQ is disabled, and the flag kept clear
+    irq_inhibit: bool,
+    /// Set at the end of the 4-step sequence, cleared by reading $4015
+    frame_irq: bool,
+    /// Last value written to $4017, written again on reset
+    frame_counter: u8,
+    /// Every other CPU cycle is an APU cycle, which clocks the channel timers
+    odd_cycle: bool,
+    /// Sets the frame sequencer timing
+    region: Region,
+}
+impl Default for Apu {
+    fn default() -> Self {
+        Self {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            frame_cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_counter: 0,
+            odd_cycle: false,
+            region: Region::default(),
+        }
+    }
+}
+impl Apu {
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+    /// Handle CPU write to $4000-$4017
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, val),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, val),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, val),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, val, &self.region.noise_periods()),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, val, &self.region.dmc_rates()),
+            // Channel enables
+            0x4015 => {
+                self.pulse1.length.set_enabled(val & 0b01 != 0);
+                self.pulse2.length.set_enabled(val & 0b10 != 0);
+                self.triangle.length.set_enabled(val & 0b100 != 0);
+                self.noise.length.set_enabled(val & 0b1000 != 0);
+                self.dmc.set_enabled(val & 0b1_0000 != 0);
+            }
+            // The inhibit flag applies right away, the mode and sequencer reset after `frame_counter_delay`
+            0x4017 => {
+                self.frame_counter = val;
+                self.irq_inhibit = val & 0x40 != 0;
+                if self.irq_inhibit { self.frame_irq = false; }
+            }
+            _ => {}
+        }
+    }
+    /// Handle CPU read from $4000-$4017, returns None for write-only registers (open bus)
+    pub fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            // Status: which length counters are running and the IRQ flags, reading acknowledges the frame IRQ
+            0x4015 => {
+                let status = self.pulse1.length.active() as u8
+                    | (self.pulse2.length.active() as u8) << 1
+                    | (self.triangle.length.active() as u8) << 2
+                    | (self.noise.length.active() as u8) << 3
+                    | ((self.dmc.bytes_remaining > 0) as u8) << 4
+                    | (self.frame_irq as u8) << 6
+                    | (self.dmc.irq as u8) << 7;
+                self.frame_irq = false;
+                Some(status)
+            }
+            _ => None,
+        }
+    }
+    /// CPU cycles until a write to $4017 takes effect: 3 if it was written on an APU cycle, 4 if between them
+    pub fn frame_counter_delay(&self) -> u32 {
+        if self.odd_cycle { 4 } else { 3 }
+    }
+    /// Apply a write to $4017: set the mode and restart the sequence. Switching to 5-step immediately clocks a half frame.
+    pub fn reset_frame_counter(&mut self, val: u8) {
+        self.five_step = val & 0x80 != 0;
+        self.frame_cycle = 0;
+        if self.five_step {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+    }
+    /// Reset button: $4015 is cleared and $4017 written again with its last value, so the mode is kept
+    pub fn reset(&mut self) {
+        self.write(0x4015, 0);
+        self.frame_irq = false;
+        self.write(0x4017, self.frame_counter);
+        self.reset_frame_counter(self.frame_counter);
+    }
+    /// Run the APU for one CPU cycle
+    pub fn step(&mut self) {
+        self.clock_frame_sequencer();
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if !self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+    }
+    /// Address of the next DMC sample byte, if the DMC needs one fetched
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.fetch_request()
+    }
+    /// Hand the DMC a sample byte fetched by the DMA
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.fill(val);
+    }
+    /// DMC is asserting the CPU IRQ line
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+    /// Frame counter is asserting the CPU IRQ line
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+    /// Run the 4-step or 5-step sequence, see: https://www.nesdev.org/wiki/APU_Frame_Counter
+    fn clock_frame_sequencer(&mut self) {
+        self.frame_cycle += 1;
+        let steps = self.region.frame_counter_steps();
+        // Last cycle of the sequence, it wraps around to 0 afterwards
+        let last = if self.five_step { steps[4] } else { steps[3] } + 1;
+        let c = self.frame_cycle;
+        if c == steps[0] || c == steps[2] {
+            self.clock_quarter_frame();
+        } else if c == steps[1] || c == last - 1 {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+        // The 4-step sequence raises the flag on its last 3 cycles
+        if !self.five_step && !self.irq_inhibit && c + 2 >= last {
+            self.frame_irq = true;
+        }
+        if c == last { self.frame_cycle = 0; }
+    }
+    /// Clocks envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+    /// Clocks length counters and sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+    /// Current output level in the range 0.0..=1.0, using the nonlinear mixer formulas, see: https://www.nesdev.org/wiki/APU_Mixer
+    pub fn output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+        let tnd = self.triangle.output() as f32 / 8227.0 + self.noise.output() as f32 / 12241.0
+            + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+}
@@ -0,0 +1,119 @@
+//! Delta modulation channel at $4010-$4013, see: https://www.nesdev.org/wiki/APU_DMC
+//! Samples are 1-bit deltas read from CPU memory. The channel only asks for bytes, they are fetched by the DMC DMA in
+//! `State`, which halts the CPU and reads through the bus like the CPU does.
+
+#[derive(Debug, Clone)]
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    /// Output rate in CPU cycles, from the region's rate table
+    rate: u16,
+    timer: u16,
+    /// 7-bit output level
+    level: u8,
+    /// Start address and length of the sample set by $4012 / $4013
+    sample_addr: u16,
+    sample_len: u16,
+    /// Next byte of the sample to fetch, and how many are left
+    addr: u16,
+    pub bytes_remaining: u16,
+    /// Byte fetched ahead, waiting to be played
+    buffer: Option<u8>,
+    /// Byte being played, one bit per output clock
+    shift: u8,
+    bits_remaining: u8,
+    /// The buffer was empty when the last byte finished, the level is held
+    silence: bool,
+    /// Sample ended without looping while the IRQ was enabled
+    pub irq: bool,
+}
+impl Default for Dmc {
+    fn default() -> Self {
+        Self {
+            irq_enabled: false, looping: false, rate: 428, timer: 0, level: 0,
+            sample_addr: 0xC000, sample_len: 1, addr: 0xC000, bytes_remaining: 0,
+            buffer: None, shift: 0, bits_remaining: 8, silence: true, irq: false,
+        }
+    }
+}
+impl Dmc {
+    /// Handle a write to one of the channel's registers (0-3)
+    pub fn write(&mut self, reg: u16, val: u8, rates: &[u16; 16]) {
+        match reg {
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled { self.irq = false; }
+                self.looping = val & 0x40 != 0;
+                self.rate = rates[(val & 0b1111) as usize];
+            }
+            1 => self.level = val & 0x7F,
+            2 => self.sample_addr = 0xC000 | (val as u16) << 6,
+            3 => self.sample_len = (val as u16) << 4 | 1,
+            _ => unreachable!(),
+        }
+    }
+    /// Enable or disable through $4015. Enabling only restarts the sample if the last one has finished.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+    /// Address of the byte to fetch if the buffer is empty and the sample isn't over
+    pub fn fetch_request(&self) -> Option<u16> {
+        (self.buffer.is_none() && self.bytes_remaining > 0).then_some(self.addr)
+    }
+    /// Fill the buffer with a byte read by the DMA
+    pub fn fill(&mut self, val: u8) {
+        if self.bytes_remaining == 0 { return }
+        self.buffer = Some(val);
+        // Wraps around to $8000, not $0000
+        self.addr = self.addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return
+        }
+        self.timer = self.rate - 1;
+        if !self.silence {
+            // Move the level by 2 unless it would leave the 0-127 range
+            if self.shift & 1 != 0 {
+                if self.level <= 125 { self.level += 2; }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.shift = byte;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+    /// Current output level (0-127)
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
@@ -0,0 +1,63 @@
+//! Noise channel at $400C-$400F, see: https://www.nesdev.org/wiki/APU_Noise
+
+use super::units::{Envelope, LengthCounter};
+
+#[derive(Debug, Clone)]
+pub struct Noise {
+    /// 15-bit linear feedback shift register
+    shift: u16,
+    /// Take feedback from bit 6 instead of bit 1, for a short 93-step sequence that sounds metallic
+    short_mode: bool,
+    /// Timer period in CPU cycles, from the region's period table
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    pub length: LengthCounter,
+}
+impl Default for Noise {
+    fn default() -> Self {
+        Self { shift: 1, short_mode: false, period: 4, timer: 0, envelope: Envelope::default(), length: LengthCounter::default() }
+    }
+}
+impl Noise {
+    /// Handle a write to one of the channel's registers (0, 2 or 3, register 1 is unused)
+    pub fn write(&mut self, reg: u16, val: u8, periods: &[u16; 16]) {
+        match reg {
+            0 => {
+                self.length.halt = val & 0b10_0000 != 0;
+                self.envelope.write(val);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = val & 0x80 != 0;
+                self.period = periods[(val & 0b1111) as usize];
+            }
+            3 => {
+                self.length.load(val);
+                self.envelope.start = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ self.shift >> tap) & 1;
+            self.shift = self.shift >> 1 | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+    /// Current output level (0-15)
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 == 0 && self.length.active() { self.envelope.output() } else { 0 }
+    }
+}
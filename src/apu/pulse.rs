@@ -0,0 +1,116 @@
+//! Pulse (square wave) channels at $4000-$4003 and $4004-$4007, see: https://www.nesdev.org/wiki/APU_Pulse
+
+use super::units::{Envelope, LengthCounter};
+
+/// Waveforms for the 4 duty cycles (12.5%, 25%, 50%, 25% negated).
+/// The sequencer counts down from 0, so it reads them in the order 0, 7, 6, ..., 1.
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Periodically bends the pitch up or down, see: https://www.nesdev.org/wiki/APU_Sweep
+#[derive(Debug, Clone, Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    /// Pulse 1 negates with ones' complement (subtracts one more than pulse 2)
+    ones_complement: bool,
+    divider: u8,
+    /// Reload the divider on the next half frame
+    reload: bool,
+}
+impl Sweep {
+    /// Period the sweep unit would change the timer to. Computed continuously, even when disabled.
+    fn target(&self, period: u16) -> u16 {
+        let change = period >> self.shift;
+        match (self.negate, self.ones_complement) {
+            (false, _) => period + change,
+            (true, true) => period.saturating_sub(change + 1),
+            (true, false) => period.saturating_sub(change),
+        }
+    }
+    /// Periods under 8 or a target over $7FF silence the channel
+    fn muting(&self, period: u16) -> bool {
+        period < 8 || self.target(period) > 0x7FF
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pulse {
+    duty: u8,
+    /// Position in the duty waveform (0-7)
+    step: u8,
+    /// 11-bit timer period in APU cycles
+    period: u16,
+    timer: u16,
+    sweep: Sweep,
+    envelope: Envelope,
+    pub length: LengthCounter,
+}
+impl Pulse {
+    /// Pulse 1 and 2 only differ in how the sweep unit negates
+    pub fn new(ones_complement: bool) -> Self {
+        Self { sweep: Sweep { ones_complement, ..Default::default() }, ..Default::default() }
+    }
+    /// Handle a write to one of the channel's 4 registers (0-3)
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.length.halt = val & 0b10_0000 != 0;
+                self.envelope.write(val);
+            }
+            1 => {
+                self.sweep.enabled = val & 0x80 != 0;
+                self.sweep.period = val >> 4 & 0b111;
+                self.sweep.negate = val & 0b1000 != 0;
+                self.sweep.shift = val & 0b111;
+                self.sweep.reload = true;
+            }
+            2 => self.period = self.period & 0x700 | val as u16,
+            3 => {
+                self.period = self.period & 0xFF | ((val & 0b111) as u16) << 8;
+                self.length.load(val);
+                // Restart the waveform and envelope, the timer keeps running
+                self.step = 0;
+                self.envelope.start = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Clocked every APU cycle (2 CPU cycles)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = self.step.wrapping_sub(1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        let sweep = &mut self.sweep;
+        if sweep.divider == 0 && sweep.enabled && sweep.shift > 0 && !sweep.muting(self.period) {
+            self.period = sweep.target(self.period);
+        }
+        if sweep.divider == 0 || sweep.reload {
+            sweep.divider = sweep.period;
+            sweep.reload = false;
+        } else {
+            sweep.divider -= 1;
+        }
+    }
+    /// Current output level (0-15)
+    pub fn output(&self) -> u8 {
+        let high = DUTY_TABLE[self.duty as usize][self.step as usize] != 0;
+        if high && self.length.active() && !self.sweep.muting(self.period) { self.envelope.output() } else { 0 }
+    }
+}
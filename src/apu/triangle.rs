@@ -0,0 +1,73 @@
+//! Triangle channel at $4008-$400B, see: https://www.nesdev.org/wiki/APU_Triangle
+
+use super::units::LengthCounter;
+
+/// 32-step waveform, 15 down to 0 and back up
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct Triangle {
+    /// Position in the waveform (0-31)
+    step: u8,
+    /// 11-bit timer period in CPU cycles
+    period: u16,
+    timer: u16,
+    /// Second, finer grained length counter, clocked by quarter frames
+    linear_counter: u8,
+    linear_reload_value: u8,
+    /// Reload the linear counter on the next quarter frame
+    linear_reload: bool,
+    /// Keeps reloading the linear counter, also halts the length counter
+    control: bool,
+    pub length: LengthCounter,
+}
+impl Triangle {
+    /// Handle a write to one of the channel's registers (0, 2 or 3, register 1 is unused)
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = val & 0x7F;
+            }
+            1 => {}
+            2 => self.period = self.period & 0x700 | val as u16,
+            3 => {
+                self.period = self.period & 0xFF | ((val & 0b111) as u16) << 8;
+                self.length.load(val);
+                self.linear_reload = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // Periods under 2 are ultrasonic, the waveform is held instead of producing a pop-inducing average level
+            if self.linear_counter > 0 && self.length.active() && self.period >= 2 {
+                self.step = (self.step + 1) % SEQUENCE.len() as u8;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control { self.linear_reload = false; }
+    }
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+    /// Current output level (0-15). Silencing the channel only stops the sequencer, the last level keeps being output.
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
@@ -0,0 +1,75 @@
+//! Building blocks shared by several channels
+
+/// Length counter load values, indexed by bits 3-7 of the channel's 4th register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Silences the channel once it counts down to 0, see: https://www.nesdev.org/wiki/APU_Length_Counter
+#[derive(Debug, Clone, Default)]
+pub struct LengthCounter {
+    pub counter: u8,
+    /// Counting is paused (shares its bit with the envelope loop flag / linear counter control)
+    pub halt: bool,
+    /// Cleared through $4015, which also forces the counter to 0
+    enabled: bool,
+}
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled { self.counter = 0; }
+    }
+    /// Load the counter from a write to the channel's 4th register, ignored while the channel is disabled
+    pub fn load(&mut self, val: u8) {
+        if self.enabled { self.counter = LENGTH_TABLE[(val >> 3) as usize]; }
+    }
+    /// Clocked by half frames
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 { self.counter -= 1; }
+    }
+    pub fn active(&self) -> bool { self.counter > 0 }
+}
+
+/// Volume that either stays constant or decays from 15 to 0, see: https://www.nesdev.org/wiki/APU_Envelope
+#[derive(Debug, Clone, Default)]
+pub struct Envelope {
+    /// Restart the decay on the next quarter frame
+    pub start: bool,
+    /// Restart at 15 when the decay reaches 0
+    pub looping: bool,
+    /// Output `volume` instead of the decay level
+    pub constant: bool,
+    /// Constant volume, or the period of the decay divider
+    pub volume: u8,
+    divider: u8,
+    decay: u8,
+}
+impl Envelope {
+    /// Set from the low 6 bits of the channel's 1st register
+    pub fn write(&mut self, val: u8) {
+        self.looping = val & 0b10_0000 != 0;
+        self.constant = val & 0b1_0000 != 0;
+        self.volume = val & 0b1111;
+    }
+    /// Clocked by quarter frames
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+    pub fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
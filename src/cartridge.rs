@@ -0,0 +1,430 @@
+//! Cartridge memory and mappers.
+//! A mapper decides which part of PRG ROM / PRG RAM a CPU address in cartridge space ($4020-$FFFF) refers to.
+//! Because every access goes through the mapper, any CPU address can be translated back to an exact PRG bank and
+//! file offset even after bank switching.
+
+use std::any::Any;
+
+use crate::region::Region;
+
+/// Size of the PRG ROM banks as counted by the iNES header
+pub const PRG_BANK_SIZE: usize = 0x4000;
+/// Size of PRG RAM mapped at $6000-$7FFF
+pub const PRG_RAM_SIZE: usize = 0x2000;
+/// Size of CHR RAM on boards without CHR ROM
+pub const CHR_RAM_SIZE: usize = 0x2000;
+
+/// Location that a CPU address in cartridge space resolves to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrgAddr {
+    /// Offset into PRG ROM
+    Rom(usize),
+    /// Offset into PRG RAM
+    Ram(usize),
+    /// Nothing is connected, reads return open bus
+    Unmapped,
+}
+
+/// Position of a CPU address inside the ROM file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrgLocation {
+    /// PRG bank number, counted in the mapper's bank size
+    pub bank: usize,
+    /// Size of the banks the mapper switches
+    pub bank_size: usize,
+    /// Offset inside of the bank
+    pub offset: usize,
+    /// Offset from the start of the ROM file
+    pub file_offset: usize,
+}
+impl std::fmt::Display for PrgLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PRG {}KB bank {} offset ${:04X} (file ${:06X})", self.bank_size / 1024, self.bank, self.offset, self.file_offset)
+    }
+}
+impl PrgLocation {
+    /// Bank number in 16KB units, as iNES headers and FCEUX name lists count banks
+    pub fn ines_bank(&self) -> usize {
+        (self.bank * self.bank_size + self.offset) / PRG_BANK_SIZE
+    }
+}
+
+/// Arrangement of the nametables, see: https://www.nesdev.org/wiki/Mirroring
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mirroring {
+    /// Nametables $2000 and $2400 share memory (vertical arrangement)
+    #[default]
+    Horizontal,
+    /// Nametables $2000 and $2800 share memory (horizontal arrangement)
+    Vertical,
+    /// All nametables use the first page of VRAM
+    SingleScreenA,
+    /// All nametables use the second page of VRAM
+    SingleScreenB,
+    /// Cartridge provides extra VRAM for 4 distinct nametables
+    FourScreen,
+}
+
+/// Memory mapper of a cartridge, see: https://www.nesdev.org/wiki/Mapper
+pub trait Mapper: Any {
+    /// Resolve a CPU address ($4020-$FFFF) to a location in the cartridge
+    fn map_prg(&self, addr: u16) -> PrgAddr;
+    /// Handle a CPU write into ROM space or unmapped space (usually a bank select register)
+    fn write_register(&mut self, addr: u16, val: u8) {}
+    /// Handle a CPU read from unmapped space, None if there is no register there (open bus)
+    fn read_register(&mut self, addr: u16) -> Option<u8> { None }
+    /// Run the mapper for one CPU cycle
+    fn step(&mut self) {}
+    /// Mapper is asserting the CPU IRQ line
+    fn irq(&self) -> bool { false }
+    /// Mirroring selected by the mapper, None if it is hardwired on the board
+    fn mirroring(&self) -> Option<Mirroring> { None }
+    /// Resolve a PPU address ($0000-$1FFF) to an offset into CHR ROM / RAM
+    fn map_chr(&self, addr: u16) -> usize { addr as usize }
+    /// Size of the PRG ROM banks the mapper switches, used to report bank numbers
+    fn prg_bank_size(&self) -> usize { PRG_BANK_SIZE }
+}
+
+/// Create mapper from iNES mapper number
+pub fn mapper_from_id(id: u16, prg_size: usize) -> Option<Box<dyn Mapper>> {
+    Some(match id {
+        0 => Box::new(NROM { prg_size }),
+        1 => Box::new(MMC1::new(prg_size)),
+        2 => Box::new(UxROM { prg_size, bank: 0 }),
+        3 => Box::new(CNROM { prg_size, bank: 0 }),
+        7 => Box::new(AxROM { prg_size, bank: 0 }),
+        _ => return None,
+    })
+}
+
+/// Mapper 0: 16KB or 32KB of fixed PRG ROM, 16KB ROMs are mirrored at $C000
+pub struct NROM {
+    prg_size: usize,
+}
+impl Mapper for NROM {
+    fn map_prg(&self, addr: u16) -> PrgAddr {
+        match addr {
+            0x6000..=0x7FFF => PrgAddr::Ram(addr as usize - 0x6000),
+            0x8000..=0xFFFF if self.prg_size != 0 => PrgAddr::Rom((addr as usize - 0x8000) % self.prg_size),
+            _ => PrgAddr::Unmapped,
+        }
+    }
+}
+
+/// Mapper 2: switchable 16KB bank at $8000, last bank fixed at $C000
+pub struct UxROM {
+    prg_size: usize,
+    bank: u8,
+}
+impl Mapper for UxROM {
+    fn map_prg(&self, addr: u16) -> PrgAddr {
+        let banks = self.prg_size / PRG_BANK_SIZE;
+        match addr {
+            0x6000..=0x7FFF => PrgAddr::Ram(addr as usize - 0x6000),
+            _ if banks == 0 => PrgAddr::Unmapped,
+            0x8000..=0xBFFF => PrgAddr::Rom((self.bank as usize % banks) * PRG_BANK_SIZE + (addr as usize - 0x8000)),
+            0xC000..=0xFFFF => PrgAddr::Rom((banks - 1) * PRG_BANK_SIZE + (addr as usize - 0xC000)),
+            _ => PrgAddr::Unmapped,
+        }
+    }
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 { self.bank = val; }
+    }
+}
+
+/// Mapper 3: fixed PRG like NROM, switchable 8KB CHR bank
+pub struct CNROM {
+    prg_size: usize,
+    bank: u8,
+}
+impl Mapper for CNROM {
+    fn map_prg(&self, addr: u16) -> PrgAddr {
+        match addr {
+            0x8000..=0xFFFF if self.prg_size != 0 => PrgAddr::Rom((addr as usize - 0x8000) % self.prg_size),
+            _ => PrgAddr::Unmapped,
+        }
+    }
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 { self.bank = val; }
+    }
+    fn map_chr(&self, addr: u16) -> usize {
+        self.bank as usize * 0x2000 + addr as usize
+    }
+}
+
+/// Mapper 1: serially loaded registers for PRG banking, CHR banking and mirroring, see: https://www.nesdev.org/wiki/MMC1
+pub struct MMC1 {
+    prg_size: usize,
+    /// Bits written so far, a 1 in bit 0 marks the register as full after the 5th write
+    shift: u8,
+    /// Mirroring (bits 0-1), PRG bank mode (bits 2-3), CHR bank mode (bit 4)
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    /// PRG bank (bits 0-3), PRG RAM disable (bit 4)
+    prg_bank: u8,
+    /// CPU cycles since the last write, the MMC1 ignores writes on consecutive cycles (like the dummy write of RMW instructions)
+    since_write: u8,
+}
+impl MMC1 {
+    /// Size of the PRG banks switched at $8000 and $C000
+    const BANK: usize = 0x4000;
+    const SHIFT_RESET: u8 = 0b1_0000;
+    pub fn new(prg_size: usize) -> Self {
+        Self { prg_size, shift: Self::SHIFT_RESET, control: 0x0C, chr_bank0: 0, chr_bank1: 0, prg_bank: 0, since_write: u8::MAX }
+    }
+    /// 16KB bank at $8000 or $C000 (`high`)
+    fn prg_bank(&self, high: bool) -> usize {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        // 512KB boards (SUROM) use CHR bank bit 4 to select the 256KB half of PRG
+        let outer = if self.prg_size > 0x40000 { (self.chr_bank0 & 0x10) as usize } else { 0 };
+        let bank = match (self.control >> 2) & 0b11 {
+            0 | 1 => (bank & !1) | high as usize,
+            2 => if high { bank } else { 0 },
+            _ => if high { 0x0F } else { bank },
+        };
+        outer | bank
+    }
+}
+impl Mapper for MMC1 {
+    fn map_prg(&self, addr: u16) -> PrgAddr {
+        let banks = self.prg_size / Self::BANK;
+        match addr {
+            0x6000..=0x7FFF if self.prg_bank & 0x10 == 0 => PrgAddr::Ram(addr as usize - 0x6000),
+            0x8000..=0xFFFF if banks != 0 => {
+                let bank = self.prg_bank(addr >= 0xC000) % banks;
+                PrgAddr::Rom(bank * Self::BANK + (addr as usize & (Self::BANK - 1)))
+            }
+            _ => PrgAddr::Unmapped,
+        }
+    }
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 { return }
+        let consecutive = self.since_write == 1;
+        self.since_write = 0;
+        if consecutive { return }
+        if val & 0x80 != 0 {
+            self.shift = Self::SHIFT_RESET;
+            self.control |= 0x0C;
+            return
+        }
+        let full = self.shift & 1 != 0;
+        self.shift = (self.shift >> 1) | ((val & 1) << 4);
+        if !full { return }
+        match addr {
+            0x8000..=0x9FFF => self.control = self.shift,
+            0xA000..=0xBFFF => self.chr_bank0 = self.shift,
+            0xC000..=0xDFFF => self.chr_bank1 = self.shift,
+            _ => self.prg_bank = self.shift,
+        }
+        self.shift = Self::SHIFT_RESET;
+    }
+    fn step(&mut self) {
+        self.since_write = self.since_write.saturating_add(1);
+    }
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0b11 {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+    fn map_chr(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        if self.control & 0x10 == 0 {
+            // One 8KB bank, low bit ignored
+            (self.chr_bank0 & !1) as usize * 0x1000 + addr
+        } else {
+            let bank = if addr < 0x1000 { self.chr_bank0 } else { self.chr_bank1 };
+            bank as usize * 0x1000 + (addr & 0x0FFF)
+        }
+    }
+}
+
+/// Mapper 7: switchable 32KB PRG bank, single-screen mirroring selected by bit 4
+pub struct AxROM {
+    prg_size: usize,
+    bank: u8,
+}
+impl Mapper for AxROM {
+    fn map_prg(&self, addr: u16) -> PrgAddr {
+        let banks = self.prg_size / 0x8000;
+        match addr {
+            0x8000..=0xFFFF if banks != 0 => {
+                PrgAddr::Rom((self.bank as usize & 0b111) % banks * 0x8000 + (addr as usize - 0x8000))
+            }
+            _ => PrgAddr::Unmapped,
+        }
+    }
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 { self.bank = val; }
+    }
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(if self.bank & 0x10 == 0 { Mirroring::SingleScreenA } else { Mirroring::SingleScreenB })
+    }
+    fn prg_bank_size(&self) -> usize { 0x8000 }
+}
+
+/// Size of the banks selected by the NSF bank registers
+pub const NSF_BANK_SIZE: usize = 0x1000;
+
+/// Memory of an NSF player: 8KB of RAM at $6000 and eight 4KB banks at $8000-$FFFF selected by $5FF8-$5FFF
+pub struct NSFMapper {
+    banks: [u8; 8],
+    bank_count: usize,
+}
+impl NSFMapper {
+    pub fn new(banks: [u8; 8], bank_count: usize) -> Self { Self { banks, bank_count } }
+}
+impl Mapper for NSFMapper {
+    fn map_prg(&self, addr: u16) -> PrgAddr {
+        match addr {
+            0x6000..=0x7FFF => PrgAddr::Ram(addr as usize - 0x6000),
+            0x8000..=0xFFFF => {
+                let bank = self.banks[(addr as usize - 0x8000) / NSF_BANK_SIZE] as usize % self.bank_count;
+                PrgAddr::Rom(bank * NSF_BANK_SIZE + (addr as usize % NSF_BANK_SIZE))
+            }
+            _ => PrgAddr::Unmapped,
+        }
+    }
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if let 0x5FF8..=0x5FFF = addr { self.banks[addr as usize - 0x5FF8] = val; }
+    }
+    fn prg_bank_size(&self) -> usize { NSF_BANK_SIZE }
+}
+
+/// Cartridge contents and the mapper that decides how they are accessed.
+pub struct Cartridge {
+    /// iNES mapper number
+    pub mapper_id: u16,
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    pub chr: Vec<u8>,
+    /// CHR is RAM and writable by the PPU
+    pub chr_ram: bool,
+    /// Mirroring hardwired on the board
+    pub mirroring: Mirroring,
+    /// PRG RAM is battery backed
+    pub battery: bool,
+    /// Timing region given by the image, None if it doesn't say
+    pub region: Option<Region>,
+    /// Where PRG ROM is stored in the ROM file, as pairs of (PRG ROM offset, file offset) sorted by PRG ROM offset.
+    /// iNES files store PRG ROM in one piece after the header and trainer, UNIF files may split it into several chunks.
+    pub prg_file_offsets: Vec<(usize, usize)>,
+    pub mapper: Box<dyn Mapper>,
+}
+impl Default for Cartridge {
+    fn default() -> Self {
+        Self {
+            mapper_id: 0,
+            prg_rom: Vec::new(),
+            prg_ram: vec![0u8; PRG_RAM_SIZE],
+            chr: Vec::new(),
+            chr_ram: false,
+            mirroring: Mirroring::default(),
+            battery: false,
+            region: None,
+            prg_file_offsets: Vec::new(),
+            mapper: Box::new(NROM { prg_size: 0 }),
+        }
+    }
+}
+impl Cartridge {
+    /// Read from cartridge space, returns None if nothing is mapped at the address
+    pub fn read(&mut self, addr: u16) -> Option<u8> {
+        match self.mapper.map_prg(addr) {
+            PrgAddr::Rom(offset) => self.prg_rom.get(offset).copied(),
+            PrgAddr::Ram(offset) => self.prg_ram.get(offset).copied(),
+            PrgAddr::Unmapped => self.mapper.read_register(addr),
+        }
+    }
+    /// Offset into CHR of a pattern table address, banks past the end of CHR wrap around
+    fn chr_offset(&self, addr: u16) -> usize {
+        self.mapper.map_chr(addr) % self.chr.len().max(1)
+    }
+    /// Read from pattern tables ($0000-$1FFF of PPU address space)
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.get(self.chr_offset(addr)).copied().unwrap_or(0)
+    }
+    /// Write to pattern tables, ignored for CHR ROM
+    pub fn write_chr(&mut self, addr: u16, val: u8) {
+        if !self.chr_ram { return }
+        let offset = self.chr_offset(addr);
+        if let Some(byte) = self.chr.get_mut(offset) { *byte = val }
+    }
+    /// Current nametable mirroring, the mapper may override the board's
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring().unwrap_or(self.mirroring)
+    }
+    /// Access the mapper as its concrete type, e.g. to swap disks of the FDS
+    pub fn mapper_mut<M: Mapper>(&mut self) -> Option<&mut M> {
+        (self.mapper.as_mut() as &mut dyn Any).downcast_mut::<M>()
+    }
+    /// Write to cartridge space, writes to ROM are passed to the mapper
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match self.mapper.map_prg(addr) {
+            PrgAddr::Ram(offset) => if let Some(byte) = self.prg_ram.get_mut(offset) { *byte = val },
+            PrgAddr::Rom(_) | PrgAddr::Unmapped => self.mapper.write_register(addr, val),
+        }
+    }
+    /// Translate a CPU address to its location in the ROM file using the current bank configuration.
+    /// Returns None if the address is not backed by PRG ROM.
+    pub fn prg_location(&self, addr: u16) -> Option<PrgLocation> {
+        match self.mapper.map_prg(addr) {
+            PrgAddr::Rom(offset) if offset < self.prg_rom.len() => {
+                let (start, file_start) = self.prg_file_offsets.iter().rev().find(|(start, _)| *start <= offset)?;
+                let bank_size = self.mapper.prg_bank_size();
+                Some(PrgLocation {
+                    bank: offset / bank_size,
+                    bank_size,
+                    offset: offset % bank_size,
+                    file_offset: file_start + (offset - start),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cnrom_switches_chr() {
+        let chr: Vec<u8> = (0..4u8).flat_map(|bank| [bank; 0x2000]).collect();
+        let mut cart = Cartridge {
+            mapper_id: 3, prg_rom: vec![0xEA; 0x8000], chr, mapper: mapper_from_id(3, 0x8000).unwrap(), ..Default::default()
+        };
+        assert_eq!(cart.read_chr(0x1FFF), 0);
+        cart.write(0x8000, 2);
+        assert_eq!(cart.read_chr(0x0000), 2);
+        assert_eq!(cart.read(0xFFFF), Some(0xEA));
+        // Only as many bank bits as there is CHR
+        cart.write(0xC123, 5);
+        assert_eq!(cart.read_chr(0x1000), 1);
+    }
+
+    #[test]
+    fn prg_location_uses_mapper_bank_size() {
+        let mut cart = Cartridge {
+            mapper_id: 7, prg_rom: vec![0; 0x20000], prg_file_offsets: vec![(0, 0x10)],
+            mapper: mapper_from_id(7, 0x20000).unwrap(), ..Default::default()
+        };
+        cart.write(0x8000, 2);
+        let loc = cart.prg_location(0xC123).unwrap();
+        assert_eq!((loc.bank, loc.offset, loc.file_offset), (2, 0x4123, 0x14133));
+        assert_eq!(loc.ines_bank(), 5);
+        assert_eq!(loc.to_string(), "PRG 32KB bank 2 offset $4123 (file $014133)");
+
+        let mut nsf = Cartridge {
+            prg_rom: vec![0; 0x8000], prg_file_offsets: vec![(0, 0x80)],
+            mapper: Box::new(NSFMapper::new([0, 1, 2, 3, 4, 5, 6, 7], 8)), ..Default::default()
+        };
+        nsf.write(0x5FF9, 6);
+        let loc = nsf.prg_location(0x9010).unwrap();
+        assert_eq!((loc.bank, loc.offset, loc.file_offset), (6, 0x010, 0x6090));
+        assert_eq!(loc.ines_bank(), 1);
+    }
+}
@@ -1,7 +1,10 @@
 
+/// Processor status flags. Declared as a plain `u8` newtype (instead of inside `bitflags!`) so it can derive
+/// `ConstParamTy` and be used as a const generic by the instruction micro-ops.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, std::marker::ConstParamTy)]
+pub struct CpuFlags(u8);
 bitflags::bitflags! {
-    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
-    pub struct CpuFlags: u8 {
+    impl CpuFlags: u8 {
         /// Negative flag. Set if the operation output's sign bit is set.
         const Negative         = 0b10000000;
         /// Overflow flag. Set an operation overflows in some form.
@@ -21,6 +24,14 @@ bitflags::bitflags! {
     }
 }
 
+impl std::fmt::Debug for CpuFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CpuFlags(")?;
+        bitflags::parser::to_writer(self, &mut *f)?;
+        f.write_str(")")
+    }
+}
+
 /// Derived from: https://www.nesdev.org/wiki/CPU_registers and https://www.nesdev.org/wiki/Status_flags
 #[derive(Default, Clone)]
 pub struct CPU {
@@ -51,15 +62,15 @@ pub struct CPU {
 impl std::fmt::Debug for CPU {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CpuState")
-        .field("a", &format_args!("0x{:X?}", &self.a))
-        .field("x", &format_args!("0x{:X?}", &self.x))
-        .field("y", &format_args!("0x{:X?}", &self.y))
+        .field("a", &format_args!("0x{:X?}", self.a))
+        .field("x", &format_args!("0x{:X?}", self.x))
+        .field("y", &format_args!("0x{:X?}", self.y))
         .field("flags", &self.flags)
-        .field("pc", &format_args!("0x{:X?}", &self.pc))
-        .field("sp", &format_args!("0x{:X?}", &self.sp))
-        .field("latch", &format_args!("0x{:X?}", &self.latch))
-        .field("first", &format_args!("0x{:X?}", &self.first))
-        .field("second", &format_args!("0x{:X?}", &self.second))
+        .field("pc", &format_args!("0x{:X?}", self.pc))
+        .field("sp", &format_args!("0x{:X?}", self.sp))
+        .field("latch", &format_args!("0x{:X?}", self.latch))
+        .field("first", &format_args!("0x{:X?}", self.first))
+        .field("second", &format_args!("0x{:X?}", self.second))
         .finish()
     }
 }
@@ -101,9 +112,9 @@ impl CPUIO {
 impl std::fmt::Debug for CPUIO {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("MemoryBus")
-        .field("low", &format_args!("0x{:X?}", &self.low))
-        .field("high", &format_args!("0x{:X?}", &self.high))
-        .field("wire", &format_args!("0x{:X?}", &self.wire))
+        .field("low", &format_args!("0x{:X?}", self.low))
+        .field("high", &format_args!("0x{:X?}", self.high))
+        .field("wire", &format_args!("0x{:X?}", self.wire))
         .finish()
     }
 }
\ No newline at end of file
@@ -0,0 +1,32 @@
+//! Disassembly of instructions for the trace log, using the operand templates of `INSTR_SET` (`LDA $nnnn,X`, `BNE $nn`, ...)
+
+use crate::{symbols::SymbolTable, Memory};
+
+/// Branches encode their operand relative to the next instruction
+fn is_branch(mnemonic: &str) -> bool {
+    matches!(mnemonic, "BPL" | "BMI" | "BVC" | "BVS" | "BCC" | "BCS" | "BNE" | "BEQ")
+}
+
+/// Fill in the operand placeholder of an instruction template.
+/// Addresses that have a symbol are shown by name, e.g. `JSR UpdatePlayer` instead of `JSR $C5F5`.
+pub fn format_instr(template: &str, addr: u16, first: Option<u8>, second: Option<u8>, symbols: &SymbolTable, mem: &Memory) -> String {
+    let mnemonic = template.split(' ').next().unwrap_or(template);
+    let (first, second) = (first.unwrap_or(0), second.unwrap_or(0));
+    let name = |target: u16, digits: usize| {
+        let bank = mem.prg_location(target).map(|loc| loc.ines_bank());
+        symbols.label(target, bank).map_or_else(|| format!("${:0digits$X}", target), str::to_owned)
+    };
+
+    if template.contains("#$nn") {
+        template.replace("$nn", &format!("${:02X}", first))
+    } else if template.contains("$nnnn") {
+        template.replace("$nnnn", &name(u16::from_le_bytes([first, second]), 4))
+    } else if template.contains("$nn") && is_branch(mnemonic) {
+        let target = addr.wrapping_add(2).wrapping_add(first as i8 as u16);
+        template.replace("$nn", &name(target, 4))
+    } else if template.contains("$nn") {
+        template.replace("$nn", &name(first as u16, 2))
+    } else {
+        template.to_owned()
+    }
+}
@@ -0,0 +1,489 @@
+//! Famicom Disk System disk images, see: https://www.nesdev.org/wiki/FDS_file_format and https://www.nesdev.org/wiki/Family_Computer_Disk_System
+//! The RAM adapter provides 32KB of PRG RAM ($6000-$DFFF), the BIOS ROM ($E000-$FFFF), 8KB of CHR RAM, an IRQ timer and
+//! the registers of the disk drive. The drive streams the selected disk side byte by byte, with the gaps and CRCs that
+//! `.fds` images leave out put back in.
+
+use std::{fs, path::{Path, PathBuf}};
+
+use crate::{State, rom::{self, ROMError}, cartridge::{Cartridge, Mapper, Mirroring, PrgAddr}};
+
+/// Size of one disk side in a `.fds` image
+pub const SIDE_SIZE: usize = 65500;
+/// Size of the BIOS ROM
+pub const BIOS_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x8000;
+const CHR_RAM_SIZE: usize = 0x2000;
+/// Gap before the first block, and between blocks, in bytes
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+/// CPU cycles it takes the drive to transfer a byte (96.4 kbit/s)
+const BYTE_CYCLES: u32 = 150;
+/// CPU cycles it takes the head to move back to the start of the disk
+const REWIND_CYCLES: u32 = 50000;
+/// CPU cycles the drive stays empty while swapping sides (about half a second), games wait to see the disk ejected
+/// before they look for the next side
+const EJECT_CYCLES: u32 = 900_000;
+
+/// A file stored on a disk side
+#[derive(Debug, Clone)]
+pub struct DiskFile {
+    pub number: u8,
+    /// Files with an id up to the boot id in the disk info block are loaded at boot
+    pub id: u8,
+    pub name: [u8; 8],
+    pub load_addr: u16,
+    /// 0: PRG RAM, 1: CHR RAM, 2: Nametable
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+/// Parsed side of a disk, along with the byte stream the drive reads from it
+#[derive(Debug, Clone)]
+pub struct DiskSide {
+    /// Disk info block (block 1) without the block code
+    pub info: Vec<u8>,
+    /// File count from the file amount block (block 2), there may be more (hidden) files on disk
+    pub file_count: u8,
+    pub files: Vec<DiskFile>,
+    /// Bytes as seen by the drive head: gaps, block start marks, blocks and CRCs
+    track: Vec<u8>,
+}
+
+/// CRC of a block as calculated by the drive
+fn block_crc(block: &[u8]) -> u16 {
+    let mut crc = 0x8000u16;
+    for byte in block.iter().chain(&[0, 0]) {
+        for bit in 0..8 {
+            let carry = crc & 1 != 0;
+            crc = (crc >> 1) | (((byte >> bit) & 1) as u16) << 15;
+            if carry { crc ^= 0x8408; }
+        }
+    }
+    crc
+}
+
+impl DiskSide {
+    /// Split a side into its blocks, stopping at the first gap of unused space
+    pub fn parse(side: &[u8]) -> Result<Self, ROMError> {
+        let block = |start: usize, len: usize| side.get(start..start + len).ok_or(ROMError::MalformedDisk("block is truncated"));
+        let mut blocks = Vec::new();
+
+        let info = block(0, 56)?;
+        if info[0] != 0x01 || &info[1..15] != b"*NINTENDO-HVC*" {
+            return Err(ROMError::MalformedDisk("side does not start with a disk info block"))
+        }
+        blocks.push(info);
+        let amount = block(56, 2)?;
+        if amount[0] != 0x02 { return Err(ROMError::MalformedDisk("missing file amount block")) }
+        blocks.push(amount);
+
+        let mut files = Vec::new();
+        let mut pos = 58;
+        while side.get(pos) == Some(&0x03) {
+            let header = block(pos, 16)?;
+            let size = u16::from_le_bytes([header[13], header[14]]) as usize;
+            let data = block(pos + 16, 1 + size)?;
+            if data[0] != 0x04 { return Err(ROMError::MalformedDisk("file header is not followed by file data")) }
+            files.push(DiskFile {
+                number: header[1],
+                id: header[2],
+                name: header[3..11].try_into().unwrap(),
+                load_addr: u16::from_le_bytes([header[11], header[12]]),
+                kind: header[15],
+                data: data[1..].to_vec(),
+            });
+            blocks.push(header);
+            blocks.push(data);
+            pos += 16 + 1 + size;
+        }
+
+        let mut track = vec![0u8; LEAD_IN_GAP];
+        for block in blocks {
+            track.push(0x80); // Block start mark
+            track.extend_from_slice(block);
+            track.extend_from_slice(&block_crc(block).to_le_bytes());
+            track.extend(std::iter::repeat_n(0, BLOCK_GAP));
+        }
+        track.resize(track.len().max(SIDE_SIZE + LEAD_IN_GAP), 0);
+
+        Ok(Self { info: info[1..].to_vec(), file_count: amount[1], files, track })
+    }
+}
+
+/// Split a disk image (with or without fwNES header) into sides
+pub fn parse_image(image: &[u8]) -> Result<Vec<DiskSide>, ROMError> {
+    let data = if image.starts_with(b"FDS\x1a") {
+        let sides = *image.get(4).ok_or(ROMError::MalformedDisk("header is truncated"))? as usize;
+        let data = image.get(16..).unwrap_or_default();
+        &data[..data.len().min(sides * SIDE_SIZE)]
+    } else {
+        image
+    };
+    if data.is_empty() || data.len() % SIDE_SIZE != 0 {
+        return Err(ROMError::MalformedDisk("image is not a whole number of disk sides"))
+    }
+    data.chunks(SIDE_SIZE).map(DiskSide::parse).collect()
+}
+
+bitflags::bitflags! {
+    /// $4025 FDS control
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct FdsCtrl: u8 {
+        /// Raise IRQ when a byte has been transferred
+        const TransferIrq  = 0b1000_0000;
+        /// Start reading / writing block data
+        const ReadWriteStart = 0b0100_0000;
+        /// Drive is writing the CRC
+        const CrcControl   = 0b0001_0000;
+        /// Mirroring (0: vertical, 1: horizontal)
+        const Horizontal   = 0b0000_1000;
+        /// Transfer mode (0: write, 1: read)
+        const ReadMode     = 0b0000_0100;
+        /// Reset transfer timing to the start of the disk
+        const TransferReset = 0b0000_0010;
+        /// Turn on the drive motor
+        const Motor        = 0b0000_0001;
+    }
+}
+
+/// RAM adapter and disk drive
+pub struct FdsMapper {
+    sides: Vec<DiskSide>,
+    /// Currently inserted side
+    side: Option<usize>,
+    /// Side waiting to be inserted while the drive is empty during a swap, and the cycles left until it is
+    swap: Option<(usize, u32)>,
+
+    /// $4020/$4021 timer reload value
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+    /// $4023 bit 0
+    disk_io_enabled: bool,
+
+    ctrl: FdsCtrl,
+    write_data: u8,
+    read_data: u8,
+    /// A byte has been transferred (and the disk IRQ raised if enabled)
+    transfer_done: bool,
+    disk_irq: bool,
+    /// Position of the head in the track
+    position: usize,
+    /// Cycles until the head reaches the next byte
+    delay: u32,
+    end_of_head: bool,
+    /// Found the start mark of a block after a gap
+    gap_ended: bool,
+}
+impl FdsMapper {
+    pub fn new(sides: Vec<DiskSide>, side: Option<usize>) -> Self {
+        Self {
+            sides, side, swap: None,
+            irq_reload: 0, irq_counter: 0, irq_repeat: false, irq_enabled: false, timer_irq: false,
+            disk_io_enabled: false,
+            ctrl: FdsCtrl::empty(), write_data: 0, read_data: 0, transfer_done: false, disk_irq: false,
+            position: 0, delay: 0, end_of_head: true, gap_ended: false,
+        }
+    }
+    pub fn sides(&self) -> &[DiskSide] { &self.sides }
+    pub fn side(&self) -> Option<usize> { self.side }
+    /// Insert a side (or eject with None) right away. The head restarts at the beginning of the new side.
+    pub fn insert_side(&mut self, side: Option<usize>) -> Result<(), ROMError> {
+        if let Some(index) = side { self.check_side(index)?; }
+        self.swap = None;
+        self.put_in(side);
+        Ok(())
+    }
+    /// Swap disks like a player would: eject the disk now and insert `side` once the drive has been empty for a while
+    pub fn swap_side(&mut self, side: usize) -> Result<(), ROMError> {
+        self.check_side(side)?;
+        self.put_in(None);
+        self.swap = Some((side, EJECT_CYCLES));
+        Ok(())
+    }
+    fn check_side(&self, side: usize) -> Result<(), ROMError> {
+        if side < self.sides.len() { Ok(()) } else { Err(ROMError::InvalidDiskSide { side, sides: self.sides.len() }) }
+    }
+    fn put_in(&mut self, side: Option<usize>) {
+        self.side = side;
+        self.end_of_head = true;
+        self.gap_ended = false;
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled { return }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat { self.irq_enabled = false; }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+    fn clock_drive(&mut self) {
+        let Some(side) = self.side else { return };
+        if !self.ctrl.contains(FdsCtrl::Motor) {
+            self.end_of_head = true;
+            return
+        }
+        if self.ctrl.contains(FdsCtrl::TransferReset) { return }
+        if self.end_of_head {
+            // Head moves back to the start of the disk
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return
+        }
+        if self.delay > 0 { self.delay -= 1; return }
+
+        let track = &mut self.sides[side].track;
+        if self.ctrl.contains(FdsCtrl::ReadMode) {
+            let byte = track[self.position];
+            if !self.ctrl.contains(FdsCtrl::ReadWriteStart) {
+                self.gap_ended = false;
+            } else if !self.gap_ended && byte == 0x80 {
+                // Start mark itself is not transferred
+                self.gap_ended = true;
+            } else if self.gap_ended {
+                self.read_data = byte;
+                self.transfer_done = true;
+                if self.ctrl.contains(FdsCtrl::TransferIrq) { self.disk_irq = true; }
+            }
+        } else {
+            // Writing, gap is written until block data starts
+            track[self.position] = if self.ctrl.contains(FdsCtrl::ReadWriteStart) { self.write_data } else { 0 };
+            self.transfer_done = true;
+            if self.ctrl.contains(FdsCtrl::TransferIrq) { self.disk_irq = true; }
+        }
+
+        self.position += 1;
+        if self.position >= track.len() {
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+impl Mapper for FdsMapper {
+    fn map_prg(&self, addr: u16) -> PrgAddr {
+        match addr {
+            0x6000..=0xDFFF => PrgAddr::Ram(addr as usize - 0x6000),
+            0xE000..=0xFFFF => PrgAddr::Rom(addr as usize - 0xE000),
+            _ => PrgAddr::Unmapped,
+        }
+    }
+    /// The 8KB BIOS is the only PRG ROM
+    fn prg_bank_size(&self) -> usize { 0x2000 }
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4023 => {
+                self.disk_io_enabled = val & 1 != 0;
+                if !self.disk_io_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            _ if !self.disk_io_enabled => {}
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | val as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | (val as u16) << 8,
+            0x4022 => {
+                self.irq_repeat = val & 0b01 != 0;
+                self.irq_enabled = val & 0b10 != 0;
+                if self.irq_enabled { self.irq_counter = self.irq_reload; } else { self.timer_irq = false; }
+            }
+            0x4024 => {
+                self.write_data = val;
+                self.transfer_done = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.ctrl = FdsCtrl::from_bits_retain(val);
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+    }
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        if !self.disk_io_enabled { return None }
+        match addr {
+            // Disk status, acknowledges IRQs
+            0x4030 => {
+                let end = self.side.is_some_and(|side| self.position >= self.sides[side].track.len() - 1);
+                let out = self.timer_irq as u8 | (self.transfer_done as u8) << 1 | (end as u8) << 6;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                Some(out)
+            }
+            0x4031 => {
+                self.transfer_done = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            }
+            // Drive status: disk inserted, ready, not write protected
+            0x4032 => {
+                let ejected = self.side.is_none();
+                let not_ready = ejected || self.end_of_head || self.delay > BYTE_CYCLES;
+                Some(ejected as u8 | (not_ready as u8) << 1 | (ejected as u8) << 2)
+            }
+            // External connector, bit 7: battery good
+            0x4033 => Some(0x80),
+            _ => None,
+        }
+    }
+    fn step(&mut self) {
+        self.clock_timer();
+        if let Some((side, cycles)) = self.swap {
+            self.swap = cycles.checked_sub(1).map(|cycles| (side, cycles));
+            if self.swap.is_none() { self.put_in(Some(side)); }
+        }
+        if self.disk_io_enabled { self.clock_drive(); }
+    }
+    fn irq(&self) -> bool { self.timer_irq || self.disk_irq }
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(if self.ctrl.contains(FdsCtrl::Horizontal) { Mirroring::Horizontal } else { Mirroring::Vertical })
+    }
+}
+
+/// Load a disk image and BIOS, inserting the given side. Returns the reset vector of the BIOS.
+/// Patches are applied to the disk image, as they are made for `.fds` files.
+pub fn load_fds(path: &Path, patches: &[PathBuf], bios: &Path, side: Option<usize>, state: &mut State) -> Result<u16, ROMError> {
+    let sides = parse_image(&rom::read_patched(path, patches)?)?;
+    let bios = fs::read(bios)?;
+    if bios.len() != BIOS_SIZE { return Err(ROMError::InvalidBios(bios.len())) }
+
+    let mut mapper = FdsMapper::new(sides, None);
+    mapper.insert_side(side)?;
+    let reset = u16::from_le_bytes([bios[0x1FFC], bios[0x1FFD]]);
+    state.mem.cartridge = Cartridge {
+        prg_rom: bios,
+        prg_ram: vec![0u8; PRG_RAM_SIZE],
+        chr: vec![0u8; CHR_RAM_SIZE],
+        chr_ram: true,
+        mirroring: Mirroring::Horizontal,
+        mapper: Box::new(mapper),
+        ..Default::default()
+    };
+    Ok(reset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Side with a disk info block, a file amount block and two files
+    fn side() -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        side.extend([0x02, 2]);
+        for (number, name, addr, kind, data) in [(0, b"KYODAKU-", 0x2800u16, 2, &[1u8, 2, 3][..]), (1, b"MAIN    ", 0x6000, 0, &[0xEA])] {
+            side.extend([0x03, number, number]);
+            side.extend(name);
+            side.extend(addr.to_le_bytes());
+            side.extend((data.len() as u16).to_le_bytes());
+            side.push(kind);
+            side.push(0x04);
+            side.extend(data);
+        }
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn disk_side() {
+        let side = DiskSide::parse(&side()).unwrap();
+        assert_eq!(&side.info[..14], b"*NINTENDO-HVC*");
+        assert_eq!(side.file_count, 2);
+        assert_eq!(side.files.len(), 2);
+        let main = &side.files[1];
+        assert_eq!((main.number, main.id, &main.name, main.load_addr, main.kind), (1, 1, b"MAIN    ", 0x6000, 0));
+        assert_eq!(side.files[0].data, [1, 2, 3]);
+        assert_eq!(main.data, [0xEA]);
+
+        // The drive sees the lead-in gap, then each block after a start mark and followed by its CRC
+        let track = &side.track[LEAD_IN_GAP..];
+        assert_eq!(track[0], 0x80);
+        let info = &track[1..59];
+        assert_eq!((info[0], &info[1..15]), (0x01, &b"*NINTENDO-HVC*"[..]));
+        // Appending the CRC leaves a CRC of zero
+        assert_eq!(block_crc(info), 0);
+    }
+
+    #[test]
+    fn disk_side_errors() {
+        let error = |side: &[u8]| DiskSide::parse(side).unwrap_err();
+        assert!(matches!(error(&side()[..40]), ROMError::MalformedDisk("block is truncated")));
+        let mut bad = side();
+        bad[1] = b'#';
+        assert!(matches!(error(&bad), ROMError::MalformedDisk("side does not start with a disk info block")));
+        let mut bad = side();
+        bad[56] = 0x03;
+        assert!(matches!(error(&bad), ROMError::MalformedDisk("missing file amount block")));
+        let mut bad = side();
+        bad[58 + 16] = 0x05;
+        assert!(matches!(error(&bad), ROMError::MalformedDisk("file header is not followed by file data")));
+        // File size running past the end of the side
+        let mut bad = side();
+        bad[58 + 13..58 + 15].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        assert!(matches!(error(&bad), ROMError::MalformedDisk("block is truncated")));
+    }
+
+    #[test]
+    fn image() {
+        assert_eq!(parse_image(&[side(), side()].concat()).unwrap().len(), 2);
+        // fwNES header, the side count limits how much is read
+        let mut header = b"FDS\x1a\x01".to_vec();
+        header.resize(16, 0);
+        assert_eq!(parse_image(&[header, side(), vec![0; 10]].concat()).unwrap().len(), 1);
+
+        let error = |image: &[u8]| parse_image(image).unwrap_err();
+        assert!(matches!(error(b"FDS\x1a"), ROMError::MalformedDisk("header is truncated")));
+        assert!(matches!(error(&side()[..SIDE_SIZE - 1]), ROMError::MalformedDisk("image is not a whole number of disk sides")));
+        assert!(matches!(error(&[]), ROMError::MalformedDisk("image is not a whole number of disk sides")));
+    }
+
+    #[test]
+    fn load_applies_patches() {
+        let dir = std::env::temp_dir().join(format!("em6502-fds-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (image, patch, bios) = (dir.join("test.fds"), dir.join("test.ips"), dir.join("bios.rom"));
+        fs::write(&image, side()).unwrap();
+        // MAIN's data byte follows the info block, the amount block, KYODAKU- and MAIN's header and data block code
+        let offset = 58 + 16 + 4 + 16 + 1;
+        fs::write(&patch, [b"PATCH".as_slice(), &[0, 0, offset as u8, 0, 1, 0x60], b"EOF"].concat()).unwrap();
+        fs::write(&bios, [0u8; BIOS_SIZE]).unwrap();
+        let mut state = State::new();
+        load_fds(&image, &[patch], &bios, Some(0), &mut state).unwrap();
+        let fds = state.mem.cartridge.mapper_mut::<FdsMapper>().unwrap();
+        assert_eq!(fds.sides()[0].files[1].data, [0x60]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn swap_sides() {
+        let mut fds = FdsMapper::new(parse_image(&[side(), side()].concat()).unwrap(), Some(0));
+        fds.write_register(0x4023, 1);
+        let ejected = |fds: &mut FdsMapper| fds.read_register(0x4032).unwrap() & 1 != 0;
+        assert!(!ejected(&mut fds));
+        assert!(matches!(fds.swap_side(2), Err(ROMError::InvalidDiskSide { side: 2, sides: 2 })));
+        fds.swap_side(1).unwrap();
+        // The drive is empty until the new side goes in
+        assert_eq!(fds.side(), None);
+        for _ in 0..EJECT_CYCLES {
+            fds.step();
+            assert!(ejected(&mut fds));
+        }
+        fds.step();
+        assert_eq!(fds.side(), Some(1));
+        assert!(!ejected(&mut fds));
+        // Inserting directly cancels a swap in progress
+        fds.swap_side(0).unwrap();
+        fds.insert_side(None).unwrap();
+        for _ in 0..=EJECT_CYCLES { fds.step(); }
+        assert_eq!(fds.side(), None);
+    }
+}
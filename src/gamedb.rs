@@ -0,0 +1,108 @@
+//! Database of known good ROM dumps in the NES 2.0 XML format of nes20db: https://forums.nesdev.org/viewtopic.php?t=19940
+//! Dumps are identified by the CRC32 / SHA-1 of their PRG + CHR contents so that broken headers can be corrected.
+//!
+//! ```xml
+//! <game>
+//!   <!-- Some Game (USA).nes -->
+//!   <rom size="40960" crc32="3D0E82C8" sha1="..."/>
+//!   <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+//! </game>
+//! ```
+
+use std::{fs, path::Path};
+
+use thiserror::Error;
+
+use crate::cartridge::Mirroring;
+
+#[derive(Error, Debug)]
+pub enum GameDbError {
+    #[error("failed to read game database: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("failed to parse game database: {0}")]
+    XMLError(#[from] roxmltree::Error),
+}
+
+/// Hashes of the PRG + CHR contents of a ROM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomHash {
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+impl RomHash {
+    pub fn new(prg: &[u8], chr: &[u8]) -> Self {
+        let mut crc = crc32fast::Hasher::new();
+        let mut sha1 = sha1_smol::Sha1::new();
+        for data in [prg, chr] {
+            crc.update(data);
+            sha1.update(data);
+        }
+        Self { crc32: crc.finalize(), sha1: sha1.digest().bytes() }
+    }
+}
+impl std::fmt::Display for RomHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CRC32 {:08X} SHA-1 ", self.crc32)?;
+        self.sha1.iter().try_for_each(|b| write!(f, "{b:02X}"))
+    }
+}
+
+/// Known good header values of a dump
+#[derive(Debug, Clone)]
+pub struct GameEntry {
+    /// Name of the dump, taken from the comment in the entry
+    pub name: String,
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: u16,
+    pub submapper: u8,
+    /// Mirroring soldered on the board, None when it is controlled by the mapper
+    pub mirroring: Option<Mirroring>,
+    pub battery: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct GameDb {
+    entries: Vec<GameEntry>,
+}
+impl GameDb {
+    pub fn load(path: &Path) -> Result<Self, GameDbError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+    pub fn parse(text: &str) -> Result<Self, GameDbError> {
+        let doc = roxmltree::Document::parse(text)?;
+        let entries = doc.descendants().filter(|n| n.has_tag_name("game")).filter_map(|game| {
+            let child = |tag: &str| game.children().find(|n| n.has_tag_name(tag));
+            let rom = child("rom")?;
+            let pcb = child("pcb")?;
+            let name = game.children().find(|n| n.is_comment()).and_then(|n| n.text()).unwrap_or_default().trim();
+            let sha1 = rom.attribute("sha1").and_then(|hex| {
+                let mut out = [0u8; 20];
+                for (i, byte) in out.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+                }
+                Some(out)
+            });
+            Some(GameEntry {
+                name: name.to_owned(),
+                crc32: u32::from_str_radix(rom.attribute("crc32")?, 16).ok()?,
+                sha1,
+                mapper: pcb.attribute("mapper")?.parse().ok()?,
+                submapper: pcb.attribute("submapper").and_then(|s| s.parse().ok()).unwrap_or(0),
+                mirroring: match pcb.attribute("mirroring") {
+                    Some("H") => Some(Mirroring::Horizontal),
+                    Some("V") => Some(Mirroring::Vertical),
+                    Some("4") => Some(Mirroring::FourScreen),
+                    _ => None,
+                },
+                battery: pcb.attribute("battery") == Some("1"),
+            })
+        }).collect();
+        Ok(Self { entries })
+    }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+    /// Find the entry for a dump, the SHA-1 has to match too if the entry has one
+    pub fn find(&self, hash: &RomHash) -> Option<&GameEntry> {
+        self.entries.iter().find(|e| e.crc32 == hash.crc32 && e.sha1.is_none_or(|sha1| sha1 == hash.sha1))
+    }
+}
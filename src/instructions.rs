@@ -58,6 +58,8 @@ fn write<B: MathOp, A: MathOp>(state: &mut State) {
 }
 
 type InstrPipeline<const S: usize> = [fn(&mut State); S];
+/// Mnemonic and micro-ops of an instruction, one per cycle after the opcode fetch
+pub type Instr = (&'static str, &'static [fn(&mut State)]);
 const fn implied<M: MathOp>() -> InstrPipeline<1> {
     [read::<SetAddrPC, M>] // read next instruction byte (and throw it away)
 }
@@ -69,12 +71,43 @@ const fn immediate<M: MathOp>() -> InstrPipeline<1> {
 // BRK instruction
 const BRK: InstrPipeline<6> = [
     read::<NOP, IncPC>,             // read next instruction byte (and throw it away), increment PC
-    write::<SetAddrStack, PUSH_STACK<PCH>>,             // push PCH onto stack, decrement SP
-    write::<SetAddrStack, PUSH_STACK<PCL>>,             // push PCL onto stack, decrement SP
-    write::<SetAddrStack, PUSH_STACK<FLAGS_WITH_BRK>>,  // push FLAGS on stack (with B flag set), decrement S
+    write::<PUSH_STACK<PCH>, NOP>,                      // push PCH onto stack, decrement SP
+    write::<PUSH_STACK<PCL>, NOP>,                      // push PCL onto stack, decrement SP
+    write::<PUSH_STACK<FLAGS_WITH_BRK>, SET<{CpuFlags::InterruptDisable}>>, // push FLAGS on stack (with B flag set), decrement S, set I
     read::<SetAddrConst<0xFE, 0xFF>, Fetch<PCL>>,          // fetch PCL from 0xFFFE
     read::<SetAddrConst<0xFF, 0xFF>, Fetch<PCH>>           // fetch PCH from 0xFFFF
 ];
+#[test]
+fn test_brk() {
+    let state = &mut State::new();
+    let mut flat = Box::new([0u8; 0x10000]);
+    flat[0xFFFE..].copy_from_slice(&[0x34, 0x12]);
+    state.mem.flat = Some(flat);
+    state.cpu.sp = 0xFD;
+    state.cpu.flags = CpuFlags::Unused | CpuFlags::Carry;
+    assert_eq!(run_instr(state, 0x0300, &[0x00, 0xEA]), 7);
+    assert_eq!(state.cpu.pc, 0x1234);
+    // Return address skips the padding byte, pushed high byte first, then the flags with B set
+    let stack = &state.mem.flat.as_ref().unwrap()[0x01FB..=0x01FD];
+    assert_eq!(stack, [0x31, 0x02, 0x03]);
+    assert_eq!(state.cpu.sp, 0xFA);
+    assert!(state.cpu.flags.contains(CpuFlags::InterruptDisable));
+    assert!(!state.cpu.flags.contains(CpuFlags::Break));
+}
+/// Hardware interrupt, run in place of the fetched instruction: BRK without incrementing PC or setting the B flag.
+/// The vector is read from $FF`L`/$FF`H`.
+const fn interrupt<const L: u8, const H: u8>() -> InstrPipeline<6> {[
+    read::<SetAddrPC, NOP>,             // read next instruction byte (and throw it away)
+    write::<PUSH_STACK<PCH>, NOP>,                      // push PCH onto stack, decrement SP
+    write::<PUSH_STACK<PCL>, NOP>,                      // push PCL onto stack, decrement SP
+    write::<PUSH_STACK<FLAGS>, SET<{CpuFlags::InterruptDisable}>>, // push FLAGS on stack (B flag clear), set I
+    read::<SetAddrConst<L, 0xFF>, Fetch<PCL>>,          // fetch PCL from vector
+    read::<SetAddrConst<H, 0xFF>, Fetch<PCH>>           // fetch PCH from vector
+]}
+/// Non-maskable interrupt, vector at $FFFA
+pub const NMI: Instr = ("NMI", &interrupt::<0xFA, 0xFB>());
+/// Maskable interrupt, shares the vector at $FFFE with BRK
+pub const IRQ: Instr = ("IRQ", &interrupt::<0xFE, 0xFF>());
 /// Return from Interrupt
 const RTI: InstrPipeline<5> = [
     read::<SetAddrPC, NOP>, // read next instruction byte (and throw it away)
@@ -153,7 +186,9 @@ fn do_branch<const FLAG: CpuFlags, const STATE: bool>(state: &mut State) {
     // If in process of page cross, do early return.
     if state.op_state.contains(OpState::PageCross) {
         state.op_state.remove(OpState::PageCross);
-        PCH::set(state, PCH::get(state).wrapping_add(1)); // increment PCH
+        // increment PCH, or decrement it when branching backwards
+        let offset = state.cpu.first.unwrap_or(0) as i8;
+        PCH::set(state, PCH::get(state).wrapping_add(if offset < 0 { 0xFF } else { 1 }));
         return
     }
 
@@ -167,8 +202,12 @@ fn do_branch<const FLAG: CpuFlags, const STATE: bool>(state: &mut State) {
         // inc pc to next instruction
         IncPC::exec(state);
         SetAddrPC::exec(state);
-        /// If branching, add operand to MEM_LOW, setting page cross if needed
-        AddIndex::<FIRST, true>::exec(state);
+        /// If branching, add signed operand to MEM_LOW, setting page cross if needed
+        let offset = state.cpu.io.wire as i8;
+        let (new_low, carry) = state.cpu.io.low.overflowing_add(offset as u8);
+        state.cpu.io.low = new_low;
+        // Negative offsets cross into the previous page when the addition does not carry
+        state.op_state.set(OpState::PageCross, carry != (offset < 0));
         // make sure PCL reflects MEM_LOW
         MV::<MEM_LOW, PCL>::exec(state);
         
@@ -181,6 +220,33 @@ fn do_branch<const FLAG: CpuFlags, const STATE: bool>(state: &mut State) {
     }
 }
 
+/// Load `code` at `pc` into a flat 64KB bus and run one instruction, returns the cycles it took
+#[cfg(test)]
+fn run_instr(state: &mut State, pc: u16, code: &[u8]) -> usize {
+    let flat = state.mem.flat.get_or_insert_with(|| Box::new([0u8; 0x10000]));
+    flat[pc as usize..][..code.len()].copy_from_slice(code);
+    state.trace = false;
+    state.cpu.pc = pc;
+    state.op_state = OpState::empty();
+    let start = state.cycle_count;
+    state.step();
+    while !state.op_state.is_empty() { state.step(); }
+    state.cycle_count - start
+}
+#[test]
+fn test_branch_page_cross() {
+    let state = &mut State::new();
+    // BNE -32 from $0312 lands in the previous page
+    assert_eq!(run_instr(state, 0x0310, &[0xD0, 0xE0]), 4);
+    assert_eq!(state.cpu.pc, 0x02F2);
+    // BNE +32 from $03F2 lands in the next page
+    assert_eq!(run_instr(state, 0x03F0, &[0xD0, 0x20]), 4);
+    assert_eq!(state.cpu.pc, 0x0412);
+    // BNE -4 stays on the page
+    assert_eq!(run_instr(state, 0x0310, &[0xD0, 0xFC]), 3);
+    assert_eq!(state.cpu.pc, 0x030E);
+}
+
 /// indexed indirect addressing
 const fn indexed_indirect<const A: usize>(op: InstrPipeline<A>) -> InstrPipeline<{4 + A}> {
     join([
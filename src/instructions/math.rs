@@ -73,7 +73,7 @@ impl MathOp for SetAddrPC {
     fn exec(state: &mut State) { state.cpu.io.set(state.cpu.pc) }
 }
 /// Sets address to Stack Pointer
-pub type SetAddrStack = SetAddr<SP, ConstReg<01>>;
+pub type SetAddrStack = SetAddr<SP, ConstReg<0x01>>;
 /// Sets address to first two operands
 pub type SetAddrOP = SetAddr<FIRST, SECOND>;
 /// Sets address to constant
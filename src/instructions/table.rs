@@ -1,6 +1,6 @@
 pub use super::*;
 
-pub const INSTR_SET: [(&'static str, &'static [fn(&mut State)]); 256] = [
+pub const INSTR_SET: [Instr; 256] = [
 	("BRK",			&BRK), // 00
 	("ORA ($nn,X)",	&indexed_indirect(read_op::<ORA>())), // 01
 	("*KIL",		&[]), // 02
@@ -33,7 +33,7 @@ pub const INSTR_SET: [(&'static str, &'static [fn(&mut State)]); 256] = [
 	("ORA $nnnn,X",	&absolute_indexed::<X, _>(read_op::<ORA>())), // 1D
 	("ASL $nnnn,X",	&absolute_indexed::<X, _>(rw_op_pc::<ASL<BUS>>())), // 1E
 	("*SLO",		&[]), // 1F
-	("JSR",			&JSR), // 20
+	("JSR $nnnn",	&JSR), // 20
 	("AND ($nn,X)",	&indexed_indirect(read_op::<AND>())), // 21
 	("*KIL",		&[]), // 22
 	("*RLA",		&[]), // 23
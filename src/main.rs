@@ -1,57 +1,347 @@
 #![allow(unused)]
 #![allow(non_camel_case_types)]
-#![feature(bigint_helper_methods)]
-#![feature(generic_arg_infer)]
+#![allow(incomplete_features)]
+// Instructions, registers and file formats are named after their mnemonics / acronyms
+#![allow(clippy::upper_case_acronyms)]
 #![feature(generic_const_exprs)]
-#![feature(const_mut_refs)]
 #![feature(adt_const_params)]
 mod rom;
+mod cartridge;
+mod patch;
+mod gamedb;
+mod apu;
+mod nsf;
+mod fds;
+mod wav;
+mod symbols;
+mod disasm;
 mod instructions;
 mod cpu;
+mod ppu;
+mod palette;
+mod screenshot;
+mod ntsc;
+mod ppu_debug;
+mod region;
+mod scheduler;
+mod test_rom;
 use bitflags::bitflags;
-use instructions::{INSTR_SET, MathOp};
+use instructions::{INSTR_SET, NMI, IRQ, Instr, MathOp};
 pub use cpu::*;
-use rom::{ROMError};
+use rom::{ROMError, ImageFormat};
+use cartridge::{Cartridge, PrgLocation};
+use apu::Apu;
+use ppu::PPU;
+use symbols::{SymbolTable, SymbolError};
+use fds::FdsMapper;
+use gamedb::{GameDb, GameDbError};
+use palette::{Palette, PaletteError};
+use screenshot::{FrameFormat, ScreenshotError, Video};
+use region::Region;
+use scheduler::{Scheduler, Clock, Event, IrqSource, Ticks};
+use test_rom::TestRomError;
 
-use std::{path::PathBuf, io::{self, Read, Write}, fs};
+use std::{path::{Path, PathBuf}, io::{self, Read, Write}, fs};
 
-use clap::{Parser};
+use clap::{Args, CommandFactory, Parser, Subcommand, error::ErrorKind};
 use thiserror::Error;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Arguments {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Required binary path to run
-    bin_path: PathBuf,
+    #[arg(required = true)]
+    bin_path: Option<PathBuf>,
+    /// Print the PRG bank and file offset of every executed instruction
+    #[arg(long)]
+    trace_prg: bool,
+    /// Load the binary as a raw 6502 image (no iNES header) into a flat 64KB RAM bus.
+    /// Intel HEX (.hex) and S-record (.s19/.srec) images are detected by extension and loaded the same way.
+    #[arg(long)]
+    raw: bool,
+    /// Address the raw image is loaded at
+    #[arg(long, value_parser = parse_addr, default_value = "0x0000", requires = "raw")]
+    load_addr: u16,
+    /// Address to start executing at, overriding the reset address and any start record in the image
+    #[arg(long, value_parser = parse_addr)]
+    entry: Option<u16>,
+    /// Symbol file(s) used to label addresses in the trace: ld65 debug info (.dbg), FCEUX name lists (.nl) or VICE labels
+    #[arg(long = "symbols", value_name = "FILE")]
+    symbol_paths: Vec<PathBuf>,
+    #[command(flatten)]
+    load: LoadArgs,
+    /// Save frame N (1-based) once it has been rendered, as PPM if the path ends in .ppm and PNG otherwise.
+    /// Emulation stops after the screenshot.
+    #[arg(long, num_args = 2, value_names = ["N", "PATH"])]
+    screenshot_at_frame: Option<Vec<String>>,
+    /// Save every rendered frame in this directory, as `frame_NNNNN.png` (or `.ppm`, see --dump-format)
+    #[arg(long, value_name = "DIR")]
+    dump_frames: Option<PathBuf>,
+    /// Image format of the frames saved by --dump-frames
+    #[arg(long, value_enum, default_value_t, requires = "dump_frames")]
+    dump_format: FrameFormat,
+    /// Stop after this many frames have been rendered
+    #[arg(long)]
+    frames: Option<u64>,
+    /// Stop after this many instructions.
+    /// Cartridge images stop after 9000 instructions if no other limit is given, raw images run until they trap.
+    #[arg(long)]
+    instructions: Option<usize>,
+    /// Stop after this many CPU cycles
+    #[arg(long)]
+    cycles: Option<usize>,
+    /// Swap the FDS disk to another side (0-based) once frame N has been rendered, e.g. `--fds-swap-at 600:1`.
+    /// The disk is ejected first and the new side goes in about half a second later.
+    #[arg(long, value_name = "N:SIDE", value_parser = parse_fds_swap)]
+    fds_swap_at: Vec<(u64, usize)>,
+    /// Palette file (.pal) used for screenshots, defaults to the built-in 2C02 palette.
+    /// Either 64 RGB colors, or 512 with a set of 64 for each combination of emphasis bits.
+    #[arg(long, value_name = "FILE", conflicts_with = "ntsc")]
+    palette: Option<PathBuf>,
+    /// Simulate the NTSC composite signal for screenshots (602 pixels wide) instead of using a palette
+    #[arg(long)]
+    ntsc: bool,
+}
+
+/// Options for loading cartridge images, shared by subcommands that run a game
+#[derive(Args)]
+struct LoadArgs {
+    /// IPS, UPS or BPS patch(es) applied in order to the image when loading, the ROM file is left untouched
+    #[arg(long = "patch", value_name = "FILE")]
+    patches: Vec<PathBuf>,
+    /// NES 2.0 XML game database (nes20db) used to identify ROMs and correct bad headers.
+    /// Defaults to `nes20db.xml` in the working directory if it exists, otherwise headers are used as they are.
+    #[arg(long, value_name = "FILE")]
+    game_db: Option<PathBuf>,
+    /// FDS BIOS ROM (8KB), required to run .fds disk images
+    #[arg(long, value_name = "FILE")]
+    fds_bios: Option<PathBuf>,
+    /// Disk side inserted at startup (0-based)
+    #[arg(long, default_value_t = 0)]
+    fds_side: usize,
+    /// Console timing region, defaults to the one in the NES 2.0 header or NTSC
+    #[arg(long, value_enum)]
+    region: Option<Region>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Play an NSF / NSFe music file and write the audio to a WAV file
+    Nsf {
+        /// NSF or NSFe file to play
+        path: PathBuf,
+        /// Track to play (1-based), defaults to the starting song of the file
+        #[arg(long)]
+        track: Option<u8>,
+        /// Length of audio to render
+        #[arg(long, default_value_t = 60.0)]
+        seconds: f64,
+        /// WAV file to write
+        #[arg(short, long, default_value = "out.wav")]
+        output: PathBuf,
+    },
+    /// Run a game for a number of frames and save images of the pattern tables, nametables, palette and OAM
+    DumpPpu {
+        /// Game to run
+        path: PathBuf,
+        /// Frame after which the PPU state is dumped
+        #[arg(long, default_value_t = 1)]
+        frame: u64,
+        /// Directory the images are written to
+        #[arg(short, long, default_value = "ppu")]
+        output: PathBuf,
+        /// Palette file (.pal) used for the images, defaults to the built-in 2C02 palette
+        #[arg(long, value_name = "FILE")]
+        palette: Option<PathBuf>,
+        /// Palette RAM palette (0-7, 4-7 are the sprite palettes) used to color the pattern tables
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..8))]
+        pattern_palette: u8,
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+    /// Run a test ROM that reports its result at $6000 (like blargg's tests), exits with an error if it fails
+    TestRom {
+        /// Test ROM to run
+        path: PathBuf,
+        /// Seconds of emulated time to wait for a result
+        #[arg(long, default_value_t = 30.0)]
+        timeout: f64,
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+}
+
+/// Used when no game database is given on the command line
+const DEFAULT_GAME_DB: &str = "nes20db.xml";
+
+/// Parse a `frame:side` pair for --fds-swap-at
+fn parse_fds_swap(s: &str) -> Result<(u64, usize), String> {
+    let (frame, side) = s.split_once(':').ok_or("expected N:SIDE")?;
+    let frame = frame.parse().map_err(|err| format!("invalid frame '{frame}': {err}"))?;
+    let side = side.parse().map_err(|err| format!("invalid side '{side}': {err}"))?;
+    Ok((frame, side))
+}
+
+/// Parse an address given either in hex (0x prefix or $ prefix) or decimal
+fn parse_addr(s: &str) -> Result<u16, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("$")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
 }
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum EmulatorError {
     #[error("invalid rom format: {0}")]
     ROMError(#[from] ROMError),
+    #[error("invalid symbol file: {0}")]
+    SymbolError(#[from] SymbolError),
+    #[error("invalid game database: {0}")]
+    GameDbError(#[from] GameDbError),
+    #[error("invalid palette: {0}")]
+    PaletteError(#[from] PaletteError),
+    #[error("unable to save frame: {0}")]
+    ScreenshotError(#[from] ScreenshotError),
+    #[error("test ROM {0}")]
+    TestRomError(#[from] TestRomError),
+}
+
+/// Load a binary into memory, returns the start address if the image has one
+fn load_image(path: &Path, format: ImageFormat, load_addr: u16, args: &LoadArgs, state: &mut State) -> Result<Option<u16>, EmulatorError> {
+    Ok(match format {
+        ImageFormat::INES => {
+            let db = match &args.game_db {
+                Some(db_path) => GameDb::load(db_path)?,
+                None if Path::new(DEFAULT_GAME_DB).exists() => GameDb::load(Path::new(DEFAULT_GAME_DB))?,
+                None => GameDb::default(),
+            };
+            let info = rom::load_rom(path, &args.patches, &db, state)?;
+            match &info.db_entry {
+                Some(entry) => println!("Game database match: {} ({})", entry.name, info.hash),
+                None if !db.is_empty() => println!("No game database match ({})", info.hash),
+                None => println!(
+                    "No game database loaded, using the iNES header as is ({}). Pass --game-db or put {DEFAULT_GAME_DB} in the working directory.",
+                    info.hash,
+                ),
+            }
+            for correction in &info.corrections {
+                println!("Corrected header: {correction}");
+            }
+            None
+        },
+        ImageFormat::Raw => { rom::load_raw(path, &args.patches, load_addr, state)?; None },
+        ImageFormat::IntelHex => rom::load_intel_hex(path, &args.patches, state)?,
+        ImageFormat::SRecord => rom::load_srec(path, &args.patches, state)?,
+        ImageFormat::UNIF => { rom::load_unif(path, &args.patches, state)?; None },
+        ImageFormat::FDS => {
+            let bios = args.fds_bios.as_ref().ok_or(ROMError::MissingBios)?;
+            Some(fds::load_fds(path, &args.patches, bios, Some(args.fds_side), state)?)
+        }
+    })
+}
+
+/// Region chosen on the command line, otherwise the one given by the loaded image
+fn select_region(args: &LoadArgs, state: &State) -> Region {
+    args.region.or(state.mem.cartridge.region).unwrap_or_default()
 }
 
 fn main() -> Result<(), EmulatorError> {
     let args = Arguments::parse();
 
-    let path = args.bin_path;
+    if let Some(Command::Nsf { path, track, seconds, output }) = &args.command {
+        nsf::render(path, *track, *seconds, output)?;
+        return Ok(())
+    }
+    if let Some(Command::DumpPpu { path, frame, output, palette, pattern_palette, load }) = &args.command {
+        let mut state = State::new();
+        state.trace = false;
+        let image_entry = load_image(path, ImageFormat::from_path(path), 0, load, &mut state)?;
+        let region = select_region(load, &state);
+        state.set_region(region);
+        state.reset();
+        if let Some(entry) = image_entry { state.cpu.pc = entry; }
+        while state.mem.ppu.frame_count < *frame && state.step() {}
+        let palette = match palette {
+            Some(path) => Palette::load(path)?,
+            None => Palette::default(),
+        }.with_swapped_emphasis(region.swaps_emphasis());
+        ppu_debug::dump(&state.mem.ppu, &state.mem.cartridge, &palette, *pattern_palette, output)?;
+        return Ok(())
+    }
+    if let Some(Command::TestRom { path, timeout, load }) = &args.command {
+        let mut state = State::new();
+        state.trace = false;
+        load_image(path, ImageFormat::from_path(path), 0, load, &mut state)?;
+        state.set_region(select_region(load, &state));
+        state.reset();
+        println!("{}", test_rom::run(&mut state, *timeout)?);
+        return Ok(())
+    }
+    let path = args.bin_path.clone().expect("binary path is required without subcommand");
+    let screenshot = args.screenshot_at_frame.as_ref().map(|values| match values[0].parse::<u64>() {
+        Ok(frame) => (frame, PathBuf::from(&values[1])),
+        Err(err) => Arguments::command()
+            .error(ErrorKind::ValueValidation, format!("invalid frame number '{}': {err}", values[0]))
+            .exit(),
+    });
+    if let Some(dir) = &args.dump_frames {
+        fs::create_dir_all(dir).map_err(ScreenshotError::from)?;
+    }
 
     // println!("Loading binary: {:?}", path);
 
     let mut state = State::new();
+    state.trace_prg = args.trace_prg;
+    for path in &args.symbol_paths {
+        state.symbols.load(path)?;
+    }
 
-    let rom = rom::load_rom(&path, &mut state)?;
+    let format = if args.raw { ImageFormat::Raw } else { ImageFormat::from_path(&path) };
+    // Start address given by the image itself
+    let image_entry = load_image(&path, format, args.load_addr, &args.load, &mut state)?;
+    let region = select_region(&args.load, &state);
+    state.set_region(region);
+    let video = match &args.palette {
+        _ if args.ntsc => Video::Ntsc,
+        Some(path) => Video::Palette(Palette::load(path)?.with_swapped_emphasis(region.swaps_emphasis())),
+        None => Video::Palette(Palette::default().with_swapped_emphasis(region.swaps_emphasis())),
+    };
 
     state.reset();
+    if let Some(entry) = args.entry.or(image_entry) { state.cpu.pc = entry; }
     // println!("Start: {state:?}");
-    let unused_bytes = state.mem.cartridge.len() - (rom.len() - 0x10);
-    // println!("Executing byte 0x{:x?} in ROM", state.cpu.pc - 0x4020 - (unused_bytes as u16) + 0x10);
-    
+
+    // Run until the last frame that is needed, or for a fixed number of instructions if no limit is given
+    let last_frame = [args.frames, screenshot.as_ref().map(|(frame, _)| *frame)].into_iter().flatten().max();
+    let unlimited = last_frame.is_none() && args.cycles.is_none() && state.mem.flat.is_none();
+    let instructions = args.instructions.or(unlimited.then_some(9000));
+    let mut frame = state.mem.ppu.frame_count;
     while state.step() {
         // println!("State: {state:?}");
-        if state.instr_count > 9000 { println!("BROKE"); break; }
-        // if state.cpu.pc == 0 { println!("reached end"); break }
-        //println!("Executing instruction: `{:?}` at byte 0x{:x?} in ROM", INSTR_SET[state.instr_indx].0, byte_num);
+        if state.mem.ppu.frame_count != frame {
+            frame = state.mem.ppu.frame_count;
+            let (framebuffer, phase) = (state.mem.ppu.framebuffer.as_slice(), state.mem.ppu.frame_phase);
+            if let Some(dir) = &args.dump_frames {
+                screenshot::write_frame(&dir.join(format!("frame_{frame:05}.{}", args.dump_format.extension())), framebuffer, phase, &video)?;
+            }
+            if let Some((_, path)) = screenshot.as_ref().filter(|(at, _)| *at == frame) {
+                screenshot::write_frame(path, framebuffer, phase, &video)?;
+            }
+            for &(_, side) in args.fds_swap_at.iter().filter(|(at, _)| *at == frame) {
+                state.swap_fds_side(side)?;
+            }
+            if last_frame.is_some_and(|last| frame >= last) { break }
+        }
+        if instructions.is_some_and(|limit| state.instr_count > limit) { break }
+        if args.cycles.is_some_and(|limit| state.cycle_count >= limit) { break }
+        // Raw test programs signal success or failure by looping on the same instruction, there are no interrupts to wait for
+        if state.trapped && state.mem.flat.is_some() {
+            println!("Trapped at ${:04X}", state.log.opcode_addr);
+            break
+        }
     }
 
     println!("Final: {state:?}");
@@ -66,25 +356,35 @@ fn main() -> Result<(), EmulatorError> {
 pub struct Memory {
     /// 2KB of internal RAM
     ram: [u8; 0x0800],
-    /// Picture Processing Unit Registers
-    ppu: [u8; 0x0008],
-    /// Audio Processing Unit Registers
-    apu: [u8; 0x0018],
+    /// Picture Processing Unit
+    ppu: PPU,
+    /// Audio Processing Unit
+    apu: Apu,
     /// Testing registers
     test: [u8; 0x0008],
-    /// Data mapped from cartridge, may be writable.
-    cartridge: [u8; 0xBFE0],
-    bytes_unused: u16,
+    /// Cartridge PRG ROM / RAM, accessed through its mapper
+    cartridge: Cartridge,
+    /// Last value driven on the data bus, returned when reading unmapped addresses
+    open_bus: u8,
+    /// Flat 64KB of RAM replacing the whole NES memory map, used for raw 6502 binaries
+    flat: Option<Box<[u8; 0x10000]>>,
+    /// Master clock and pending timed events
+    scheduler: Scheduler,
+    /// Sources asserting the CPU IRQ line
+    irq: IrqSource,
 }
 impl Memory {
     fn new() -> Self {
         Self {
             ram: [0u8; 0x0800],
-            ppu: [0u8; 0x0008],
-            apu: [0u8; 0x0018],
+            ppu: PPU::default(),
+            apu: Apu::default(),
             test: [0u8; 0x0008],
-            cartridge: [0u8; 0xBFE0],
-            bytes_unused: 0xBFE0,
+            cartridge: Cartridge::default(),
+            open_bus: 0,
+            flat: None,
+            scheduler: Scheduler::default(),
+            irq: IrqSource::empty(),
         }
     }
     fn mem_map(&mut self, addr: u16) -> &mut u8 {
@@ -92,26 +392,47 @@ impl Memory {
         match addr {
             /// Access internal RAM (is mirrored 4 times, total size 0x0800)
             0x0000..=0x1FFF => &mut self.ram[idx % 0x0800],
-            /// Access the PPU, repeats every 8 bytes until 0x1FF8
-            0x2000..=0x3FFF => {panic!("accessed PPU"); &mut self.ppu[(idx - 0x2000) % 0x0008]},
-            0x4000..=0x4017 => {panic!("accessed APU"); &mut self.apu[idx - 0x4000]},
             0x4018..=0x401F =>{panic!("accessed APU"); &mut self.test[idx - 4018]},
-            0x4020..=0xFFFF => &mut self.cartridge[idx - 0x4020],
+            0x2000..=0x3FFF => unreachable!("PPU registers are accessed through the PPU"),
+            0x4000..=0x4017 => unreachable!("APU registers are accessed through the APU"),
+            0x4020..=0xFFFF => unreachable!("cartridge space is accessed through the mapper"),
         }
     }
-    // Converts from Memory address to ROM address. Memory Addr may not be in rom.
-    pub fn mem_to_rom(&self, addr: u16) -> Option<u16> {
-        let rom_start = 0x4020u16 + self.bytes_unused;
-        if (rom_start..=0xFFFFu16).contains(&addr) { Some((addr - rom_start) + 0x10) } else { None }
+    /// Converts from CPU address to location in the ROM file, taking the current PRG banks into account.
+    /// Returns None if the address is not backed by PRG ROM.
+    pub fn prg_location(&self, addr: u16) -> Option<PrgLocation> {
+        self.cartridge.prg_location(addr)
     }
     pub fn read(&mut self, addr: u16) -> u8 {
-        let out = self.mem_map(addr).clone();
-        // println!("READ: {addr:#06X?} = {out:#04X?} {}", self.mem_to_rom(addr).map_or(String::new(), |x|format!("({:#06X?})", x)));
+        if let Some(flat) = &self.flat { return flat[addr as usize] }
+        let out = match addr {
+            /// Access the PPU, repeats every 8 bytes until 0x3FF8
+            0x2000..=0x3FFF => self.ppu.read_register(addr, &mut self.cartridge),
+            0x4000..=0x4017 => self.apu.read(addr).unwrap_or(self.open_bus),
+            0x4020..=0xFFFF => self.cartridge.read(addr).unwrap_or(self.open_bus),
+            _ => *self.mem_map(addr),
+        };
+        self.open_bus = out;
+        // println!("READ: {addr:#06X?} = {out:#04X?} {}", self.prg_location(addr).map_or(String::new(), |x|format!("({x})")));
         out
     }
     pub fn write(&mut self, addr: u16, val: u8) {
-        // println!("WRITE: {addr:#06X?} = {val:#04X?} ({:?})", self.mem_to_rom(addr).map_or(format!("??"), |x|format!("{:#06X?}", x)));
-        *self.mem_map(addr) = val;
+        // println!("WRITE: {addr:#06X?} = {val:#04X?} ({})", self.prg_location(addr).map_or(format!("??"), |x|format!("{x}")));
+        if let Some(flat) = &mut self.flat { flat[addr as usize] = val; return }
+        self.open_bus = val;
+        match addr {
+            0x2000..=0x3FFF => self.ppu.write_register(addr, val, &mut self.cartridge),
+            // The OAM DMA starts on the next CPU cycle
+            0x4014 => self.scheduler.schedule(self.scheduler.after_cycles(1), Event::OamDma(val)),
+            0x4017 => {
+                self.apu.write(addr, val);
+                let delay = self.apu.frame_counter_delay();
+                self.scheduler.schedule(self.scheduler.after_cycles(delay), Event::FrameCounterWrite(val));
+            }
+            0x4000..=0x4017 => self.apu.write(addr, val),
+            0x4020..=0xFFFF => self.cartridge.write(addr, val),
+            _ => *self.mem_map(addr) = val,
+        }
     }
 }
 
@@ -119,11 +440,13 @@ pub struct PPUState {
     
 }
 
+/// Address that subroutines started with `State::start_call` return to, execution stops before it is fetched
+const CALL_RETURN: u16 = 0x4100;
+
 /// Derived from: https://www.nesdev.org/wiki/CPU_memory_map
 pub struct State {
     mem: Memory,
     cpu: CPU,
-    ppu: PPU,
     /// Current instruction that may be executing
     instr_indx: usize,
     /// Current cycle of the current instruction executing
@@ -133,6 +456,44 @@ pub struct State {
     cycle_count: usize,
     instr_count: usize,
     log: Logging,
+    /// Append PRG bank / file offset of each instruction to the trace log
+    trace_prg: bool,
+    /// Labels and source lines shown in the trace log
+    symbols: SymbolTable,
+    /// Print every executed instruction
+    trace: bool,
+    /// The last instruction jumped or branched to itself
+    trapped: bool,
+    /// OAM DMA in progress, the CPU is halted until it is done
+    dma: Option<OamDma>,
+    /// DMC sample fetch waiting for the CPU to halt on its next read, see: https://www.nesdev.org/wiki/DMA#DMC_DMA
+    dmc_dma: bool,
+    /// NMI seen by the interrupt poll before the last cycle of the current instruction
+    nmi_poll: bool,
+    /// IRQ seen (and not masked by the I flag) by the interrupt poll
+    irq_poll: bool,
+    /// Console timing, sets how many PPU dots run per CPU cycle
+    region: Region,
+    /// Time the PPU has caught up to, PAL runs a fractional 3.2 dots per CPU cycle
+    ppu_clock: Clock,
+    /// Time the APU and cartridge have caught up to, both run on the CPU clock
+    apu_clock: Clock,
+}
+
+/// `instr_indx` of the NMI and IRQ sequences, which run in place of an instruction
+const NMI_INDEX: usize = 0x100;
+const IRQ_INDEX: usize = 0x101;
+
+/// Copy of a page of CPU memory into OAM, see: https://www.nesdev.org/wiki/DMA#OAM_DMA
+#[derive(Debug, Clone, Copy)]
+struct OamDma {
+    page: u8,
+    /// Cycles done so far
+    cycle: u16,
+    /// 513 cycles, plus one to align with a read cycle if started on an odd CPU cycle
+    len: u16,
+    /// Byte read on the last cycle, written to OAM on the next one
+    latch: u8,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -143,12 +504,15 @@ struct Logging {
     operand: Option<u8>,
     start_cycle: usize,
     start_cpu: CPU,
+    /// Location of the opcode in the ROM file
+    prg_location: Option<PrgLocation>,
     // effective address if read/write memory
 }
 impl Logging {
     fn new_instr(state: &mut State, opcode: u8, opcode_addr: u16) {
         state.log = Logging {
             opcode, opcode_addr, start_cycle: state.cycle_count, start_cpu: state.cpu.clone(),
+            prg_location: state.mem.prg_location(opcode_addr),
             ..Default::default()
         }
     }
@@ -157,12 +521,25 @@ impl Logging {
         let cpu_str = format!("A:{:02X?} X:{:02X?} Y:{:02X?} P:{:02X?} SP:{:02X?} CYC:{}", cpu.a, cpu.x, cpu.y, cpu.flags.bits(), cpu.sp, state.log.start_cycle);
         let bytes_str = format!("{:04X?}  {:02X?}{}{}", 
             state.log.opcode_addr,
-            //state.mem.mem_to_rom(state.log.opcode_addr).map_or(String::new(), |x|format!("({:#06X?})", x)),
             state.log.opcode,
             state.cpu.first.map_or("".to_owned(), |x|format!(" {:02X?}", x)),
             state.cpu.second.map_or("".to_owned(), |x|format!(" {:02X?}", x)),
         );
-        println!("{:<15} {:<12} {}", bytes_str, instr_str.split(" ").next().unwrap(), cpu_str);
+        // Bank has to be looked up from the state at the start of the instruction, before any bank switch it did
+        let prg_str = match (state.trace_prg, state.log.prg_location) {
+            (false, _) => String::new(),
+            (true, Some(loc)) => format!(" {loc}"),
+            (true, None) => " PRG unmapped".to_owned(),
+        };
+        let instr_str = disasm::format_instr(instr_str, state.log.opcode_addr, state.cpu.first, state.cpu.second, &state.symbols, &state.mem);
+        let file_offset = state.log.prg_location.map(|loc| loc.file_offset);
+        let source_str = state.symbols.source_line(state.log.opcode_addr, file_offset)
+            .map_or(String::new(), |(file, line)| format!(" ; {file}:{line}"));
+        // Label the start of a routine
+        if let Some(label) = state.symbols.label(state.log.opcode_addr, state.log.prg_location.map(|loc| loc.ines_bank())) {
+            println!("{label}:");
+        }
+        println!("{:<15} {:<12} {}{}{}", bytes_str, instr_str, cpu_str, prg_str, source_str);
     }
     /* fn log_mem_op(state: &mut State, operand: u8) {
         state.log.last_mem = u16::from_le_bytes([state.cpu.io.low, state.cpu.io.high]);
@@ -175,29 +552,69 @@ impl State {
         State {
             mem: Memory::new(),
             cpu: Default::default(),
-            ppu: Default::default(),
             instr_indx: 0,
             cycle_idx: 0,
             cycle_count: 0,
             instr_count: 0,
             op_state: Default::default(),
             log: Default::default(),
+            trace_prg: false,
+            symbols: SymbolTable::default(),
+            trace: true,
+            trapped: false,
+            dma: None,
+            dmc_dma: false,
+            nmi_poll: false,
+            irq_poll: false,
+            region: Region::default(),
+            ppu_clock: Clock::new(Region::default().ppu_divider()),
+            apu_clock: Clock::new(Region::default().cpu_divider()),
         }
     }
+    /// Switch the console timing region
+    fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.mem.ppu.region = region;
+        self.mem.apu.set_region(region);
+        self.mem.scheduler.set_region(region);
+        self.ppu_clock.set_divider(region.ppu_divider());
+        self.apu_clock.set_divider(region.cpu_divider());
+    }
     fn reset(&mut self) {
         self.instr_count = 0;
         self.log = Logging::default();
-        let low = self.read_at(0xFFFC);
-        let high = self.read_at(0xFFFD);
-        self.cpu.pc = 0xC000;
-        // self.cpu.pc_set([low, high]);
+        self.cpu.pc = self.reset_vector();
         self.cycle_count = 7;
         self.cpu.flags = CpuFlags::Unused | CpuFlags::InterruptDisable;
         self.cpu.sp = 0xFD;
         self.read();
     }
+    /// Press the reset button: the CPU restarts at the reset vector, memory is kept and the APU is silenced
+    fn soft_reset(&mut self) {
+        self.op_state = OpState::default();
+        self.dma = None;
+        self.dmc_dma = false;
+        self.nmi_poll = false;
+        self.irq_poll = false;
+        // The reset sequence is an interrupt with the stack writes suppressed
+        self.cpu.sp = self.cpu.sp.wrapping_sub(3);
+        self.cpu.flags.insert(CpuFlags::InterruptDisable);
+        self.mem.apu.reset();
+        self.cpu.pc = self.reset_vector();
+    }
+    /// Eject the FDS disk and insert another side, see `FdsMapper::swap_side`
+    fn swap_fds_side(&mut self, side: usize) -> Result<(), ROMError> {
+        self.mem.cartridge.mapper_mut::<FdsMapper>().ok_or(ROMError::NotDiskImage)?.swap_side(side)
+    }
+    /// Address stored at $FFFC
+    fn reset_vector(&mut self) -> u16 {
+        u16::from_le_bytes([self.read_at(0xFFFC), self.read_at(0xFFFD)])
+    }
     fn read(&mut self) {
-        self.cpu.io.wire = self.mem.read(u16::from_be_bytes([self.cpu.io.high, self.cpu.io.low]));
+        let addr = u16::from_be_bytes([self.cpu.io.high, self.cpu.io.low]);
+        // Only read cycles can be halted, the CPU keeps running through write cycles and cycles without a bus access
+        if self.dmc_dma { self.dmc_stall(addr); }
+        self.cpu.io.wire = self.mem.read(addr);
     }
     fn read_at(&mut self, addr: u16) -> u8 {
         self.cpu.io.set(addr);
@@ -205,19 +622,32 @@ impl State {
         self.cpu.io.wire
     }
     fn write(&mut self) {
-        self.mem.write(u16::from_be_bytes([self.cpu.io.high, self.cpu.io.low]), self.cpu.io.wire);
+        let addr = u16::from_be_bytes([self.cpu.io.high, self.cpu.io.low]);
+        self.mem.write(addr, self.cpu.io.wire);
     }
     fn read_instr(&mut self) {
-        if self.instr_count != 0 { Logging::log(self, INSTR_SET[self.instr_indx].0); }
+        if self.trace && self.instr_count != 0 { Logging::log(self, self.instr().0); }
+        self.trapped = self.instr_count != 0 && self.cpu.pc == self.log.opcode_addr;
         // Read new instruction
-        self.instr_indx = self.read_at(self.cpu.pc) as usize;
+        let opcode = self.read_at(self.cpu.pc);
         self.cycle_idx = 0;
 
         // logging
-        Logging::new_instr(self, self.instr_indx as u8, self.cpu.pc);
+        Logging::new_instr(self, opcode, self.cpu.pc);
         self.instr_count += 1;
 
-        self.cpu.pc = self.cpu.pc.wrapping_add(1);
+        // A pending interrupt replaces the fetched instruction, PC is left pointing at it
+        if self.nmi_poll && self.mem.ppu.nmi {
+            self.mem.ppu.nmi = false;
+            self.instr_indx = NMI_INDEX;
+        } else if self.irq_poll {
+            self.instr_indx = IRQ_INDEX;
+        } else {
+            self.instr_indx = opcode as usize;
+            self.cpu.pc = self.cpu.pc.wrapping_add(1);
+        }
+        self.nmi_poll = false;
+        self.irq_poll = false;
         self.cpu.first = None;
         self.cpu.second = None;
         self.cpu.eff_addr = None;
@@ -225,6 +655,19 @@ impl State {
     }
     /// Run a single CPU cycle
     fn step(&mut self) -> bool {
+        if let Some(dma) = self.dma {
+            // A DMC fetch takes over a read cycle of the OAM DMA, which then needs a cycle to realign
+            let lead = dma.len - 512;
+            if self.dmc_dma && dma.cycle >= lead && (dma.cycle - lead) % 2 == 0 {
+                self.dmc_dma = false;
+                self.fetch_dmc_sample();
+                self.step_components();
+            } else {
+                self.step_dma();
+            }
+            self.step_components();
+            return true
+        }
         let old_cpu = self.cpu.clone();
         let old_op_state = self.op_state;
         
@@ -234,8 +677,11 @@ impl State {
         if self.op_state.contains(OpState::Branching) {
             self.op_state.remove(OpState::Branching);
         } else if self.op_state.contains(OpState::Active) {
-            let instr_set = INSTR_SET[self.instr_indx].1;
-            if instr_set.len() == 0 { Logging::log(self, INSTR_SET[self.instr_indx].0); return false }
+            let instr_set = self.instr().1;
+            if instr_set.is_empty() {
+                if self.trace { Logging::log(self, self.instr().0); }
+                return false
+            }
 
             let curren_idx = self.cycle_idx;
 
@@ -251,9 +697,117 @@ impl State {
         }
         //old.cmp(&self.cpu);
         // if old_op_state != self.op_state { println!("OP_STATE: {:?} -> {:?}", old_op_state, self.op_state); }
-        self.cycle_count += 1;
+        self.step_components();
+        // Interrupts are polled at the end of the second to last cycle of an instruction
+        if self.op_state.contains(OpState::Active) && self.cycle_idx + 1 == self.instr().1.len() {
+            self.nmi_poll = self.mem.ppu.nmi;
+            self.irq_poll = !self.mem.irq.is_empty() && !self.cpu.flags.contains(CpuFlags::InterruptDisable);
+        }
+        true
+    }
+    /// Run until the master clock reaches `ticks`, returns false if the CPU stopped before that
+    fn run_until(&mut self, ticks: Ticks) -> bool {
+        while self.mem.scheduler.now() < ticks {
+            if !self.step() { return false }
+        }
         true
     }
+    /// Mnemonic and micro-ops of the current instruction
+    fn instr(&self) -> Instr {
+        match self.instr_indx {
+            NMI_INDEX => NMI,
+            IRQ_INDEX => IRQ,
+            opcode => INSTR_SET[opcode],
+        }
+    }
+    /// Run a cycle of OAM DMA: after the halt (and alignment) cycles, alternate between reading a byte and writing it to OAMDATA
+    fn step_dma(&mut self) {
+        let Some(mut dma) = self.dma else { return };
+        let lead = dma.len - 512;
+        if dma.cycle >= lead {
+            let i = dma.cycle - lead;
+            if i % 2 == 0 {
+                dma.latch = self.mem.read(u16::from_be_bytes([dma.page, (i / 2) as u8]));
+            } else {
+                self.mem.write(0x2004, dma.latch);
+            }
+        }
+        dma.cycle += 1;
+        self.dma = (dma.cycle < dma.len).then_some(dma);
+    }
+    /// Stall the CPU on its read of `addr` for a DMC fetch: halt, dummy, alignment until a get (odd) cycle, then get.
+    /// The halted CPU keeps driving its read, repeating side effects of registers like $2007 and $4016, and the value
+    /// it ends up with is the one read after the stall.
+    fn dmc_stall(&mut self, addr: u16) {
+        for _ in 0..2 {
+            self.mem.read(addr);
+            self.step_components();
+        }
+        if self.cycle_count % 2 == 0 {
+            self.mem.read(addr);
+            self.step_components();
+        }
+        self.dmc_dma = false;
+        self.fetch_dmc_sample();
+        self.step_components();
+    }
+    fn fetch_dmc_sample(&mut self) {
+        if let Some(addr) = self.mem.apu.dmc_request() {
+            let val = self.mem.read(addr);
+            self.mem.apu.dmc_fill(val);
+        }
+    }
+    /// Run a cycle where the CPU does nothing and only the other components advance
+    fn idle(&mut self) {
+        // Nothing to halt, DMC samples are fetched right away
+        if std::mem::take(&mut self.dmc_dma) { self.fetch_dmc_sample(); }
+        self.step_components();
+    }
+    /// Advance the master clock by one CPU cycle, catch everything besides the CPU up to it and dispatch due events
+    fn step_components(&mut self) {
+        let now = self.mem.scheduler.advance_cpu();
+        self.cycle_count += 1;
+        // Raw binaries run on a bare 6502 with flat RAM: there is no PPU, APU or cartridge to raise NMI or IRQ,
+        // and their registers can't be reached to turn them off (the APU powers on with the frame IRQ enabled)
+        if self.mem.flat.is_some() { return }
+        // 3 dots per CPU cycle on NTSC, 3.2 on PAL
+        while self.ppu_clock.tick(now) {
+            self.mem.ppu.step(&self.mem.cartridge);
+        }
+        while self.apu_clock.tick(now) {
+            self.mem.apu.step();
+            self.mem.cartridge.mapper.step();
+        }
+        if self.mem.apu.dmc_request().is_some() { self.dmc_dma = true; }
+        self.mem.irq.set(IrqSource::Mapper, self.mem.cartridge.mapper.irq());
+        self.mem.irq.set(IrqSource::Dmc, self.mem.apu.dmc_irq());
+        self.mem.irq.set(IrqSource::FrameCounter, self.mem.apu.frame_irq());
+        while let Some(event) = self.mem.scheduler.pop_due() {
+            self.dispatch(event);
+        }
+    }
+    fn dispatch(&mut self, event: Event) {
+        match event {
+            Event::OamDma(page) => {
+                let len = if self.cycle_count % 2 == 1 { 514 } else { 513 };
+                self.dma = Some(OamDma { page, cycle: 0, len, latch: 0 });
+            }
+            Event::FrameCounterWrite(val) => self.mem.apu.reset_frame_counter(val),
+        }
+    }
+    /// Jump to a subroutine as if it was called with JSR from `CALL_RETURN`. Step until `in_call` is false to run it.
+    fn start_call(&mut self, addr: u16) {
+        let [low, high] = CALL_RETURN.wrapping_sub(1).to_le_bytes();
+        self.mem.write(0x0100 | self.cpu.sp as u16, high);
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        self.mem.write(0x0100 | self.cpu.sp as u16, low);
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        self.cpu.pc = addr;
+    }
+    /// Subroutine started by `start_call` has not returned yet
+    fn in_call(&self) -> bool {
+        self.cpu.pc != CALL_RETURN || self.op_state.intersects(OpState::Active | OpState::Branching)
+    }
 }
 impl std::fmt::Debug for State {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -275,64 +829,103 @@ bitflags::bitflags! {
     }
 }
 
-#[derive(Default, Debug, Clone)]
-struct PPU {
-    io: PPUIO,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ppu::PPUStatus;
 
-#[derive(Default, Debug, Clone)]
-/// Manages the I/O state of the PPU
-struct PPUIO {
-    /// Control flags
-    ctrl: PPUCtrl,
-    /// Mask flags
-    mask: PPUMask,
-    /// Status flags
-    status: u8,
-    oam_addr: u8,
-    oam_data: u8,
-    scroll: u8,
-    addr: u8,
-
-    dma: u8,
-}
-bitflags::bitflags! {
-    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
-    pub struct PPUCtrl: u8 {
-        /// Generate an NMI (Non-Maskable Interrupt) at the start of the vertical blanking interval (0: off; 1: on)
-        const VBlankNMI      = 0b1000_0000;
-        /// (0: read backdrop from EXT pins; 1: output color on EXT pins)
-        const EXTCtrlSelect  = 0b0100_0000;
-        /// Sprite size (0: 8x8 pixels; 1: 8x16 pixels – see PPU OAM#Byte 1)
-        const SpriteSize     = 0b0010_0000;
-        /// Background Pattern Table Addr: 0=$0000, 1=$1000
-        const BgPatTblAddr   = 0b0001_0000;
-        /// Sprite pattern table address for 8x8 sprites (0: $0000; 1: $1000; ignored in 8x16 mode)
-        const PatTblAddrType = 0b0000_1000;
-        /// VRAM address increment per CPU read/write of PPUDATA (0: add 1, going across; 1: add 32, going down)
-        const VRAMAddrInc    = 0b0000_0010;
-        /// Base Nametable Addr: 0=$2000, 1=$2400, 2=$2800, 3=$2C00
-        const BaseNameAddr = 0b0000_0011;
+    #[test]
+    fn dmc_stall_keeps_final_read() {
+        let mut state = State::new();
+        state.mem.ppu.io.status.insert(PPUStatus::VBlank);
+        state.dmc_dma = true;
+        let start = state.cycle_count;
+        // The halt cycle's read of PPUSTATUS clears vblank, the CPU gets the value of the read after the stall
+        state.read_at(0x2002);
+        assert_eq!(state.cpu.io.wire & 0x80, 0);
+        assert!(matches!(state.cycle_count - start, 3 | 4));
+        assert!(!state.dmc_dma);
     }
-}
-bitflags::bitflags! {
-    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
-    pub struct PPUMask: u8 {
-        /// Emphasize blue
-        const EmphasizeBlue = 0b1000_0000;
-        /// Emphasize green (red on PAL/Dendy)
-        const EmphasizeGreen = 0b0100_0000;
-        /// Emphasize red (green on PAL/Dendy)
-        const EmphasizeRed = 0b0010_0000;
-        /// Show sprites (0: hide, 1: show)
-        const SpritesShow = 0b0001_0000;
-        /// Show background (0: hide, 1: show)
-        const BgShow = 0b0000_1000;
-        /// Show sprites in leftmost 8 pixels of screen (0: hide, 1: show)
-        const SpritesLeftmostShow = 0b0000_0100;
-        /// Show background in leftmost 8 pixels of screen (0: hide, 1: show)
-        const BgLeftmostShow = 0b0000_0010;
-        /// Produce a greyscale display (0: normal color, 1: greyscale)
-        const Greyscale = 0b0000_0001;
+
+    #[test]
+    fn dmc_stall_waits_for_read() {
+        let mut state = State::new();
+        state.dmc_dma = true;
+        let start = state.cycle_count;
+        state.cpu.io.set(0x0200);
+        state.write();
+        assert_eq!(state.cycle_count, start);
+        assert!(state.dmc_dma);
+    }
+
+    /// Run an OAM DMA of page 2 to the end, optionally enabling the DMC halfway, returns the cycles it took
+    /// and the length it should take on its own
+    fn run_oam_dma(state: &mut State, enable_dmc: bool) -> (usize, usize) {
+        let start = state.cycle_count;
+        let len = if start % 2 == 1 { 514 } else { 513 };
+        state.dispatch(Event::OamDma(0x02));
+        for _ in 0..100 { state.step(); }
+        if enable_dmc { state.mem.write(0x4015, 0x10); }
+        while state.dma.is_some() { state.step(); }
+        (state.cycle_count - start, len)
+    }
+
+    #[test]
+    fn sprite_dma_and_dmc_dma() {
+        let mut state = State::new();
+        state.trace = false;
+        for i in 0..0x100 { state.mem.write(0x0200 + i, i as u8); }
+        // Fastest rate, no loop or IRQ, a single byte sample
+        for (addr, val) in [(0x4010, 0x0F), (0x4012, 0x00), (0x4013, 0x00)] { state.mem.write(addr, val); }
+
+        let (cycles, len) = run_oam_dma(&mut state, false);
+        assert_eq!(cycles, len);
+        assert!(state.mem.ppu.oam.iter().enumerate().all(|(i, &val)| val == i as u8));
+
+        // The DMC fetch takes over a get cycle, and the OAM DMA needs another to realign
+        state.mem.ppu.oam = [0; 0x100];
+        let (cycles, len) = run_oam_dma(&mut state, true);
+        assert_eq!(cycles, len + 2);
+        assert!(state.mem.apu.dmc_request().is_none());
+        assert!(state.mem.ppu.oam.iter().enumerate().all(|(i, &val)| val == i as u8));
+    }
+
+    #[test]
+    fn raw_binaries_get_no_interrupts() {
+        let mut state = State::new();
+        state.trace = false;
+        let mut flat = Box::new([0u8; 0x10000]);
+        // CLI; loop: NOP; JMP loop, with the interrupt vectors pointing at a BRK handler at $0500
+        flat[0x0400..0x0405].copy_from_slice(&[0x58, 0xEA, 0x4C, 0x01, 0x04]);
+        flat[0xFFFA..].copy_from_slice(&[0x00, 0x05, 0x00, 0x04, 0x00, 0x05]);
+        state.mem.flat = Some(flat);
+        state.reset();
+        // Well past the first APU frame IRQ at 29830 cycles, and a few vblanks
+        while state.cycle_count < 100_000 {
+            state.step();
+            assert!((0x0400..0x0406).contains(&state.cpu.pc), "left the loop at {:#06X}", state.cpu.pc);
+        }
+        assert!(!state.cpu.flags.contains(CpuFlags::InterruptDisable));
+    }
+
+    #[test]
+    fn jump_to_itself_traps() {
+        let mut state = State::new();
+        state.trace = false;
+        let mut flat = Box::new([0u8; 0x10000]);
+        // LDX #3; loop: DEX; BNE loop; JMP *
+        flat[0x0400..0x0408].copy_from_slice(&[0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x4C, 0x05, 0x04]);
+        flat[0xFFFC..0xFFFE].copy_from_slice(&[0x00, 0x04]);
+        state.mem.flat = Some(flat);
+        state.reset();
+        let mut instructions = 0;
+        while !state.trapped {
+            state.step();
+            instructions = state.instr_count;
+            assert!(instructions < 100);
+        }
+        // The branch back to DEX is not a trap, the second fetch of the JMP is
+        assert_eq!(instructions, 9);
+        assert_eq!(state.log.opcode_addr, 0x0405);
     }
 }
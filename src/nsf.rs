@@ -0,0 +1,324 @@
+//! NES Sound Format music files: https://www.nesdev.org/wiki/NSF and https://www.nesdev.org/wiki/NSFe
+//! The tune is played by calling its INIT routine once and its PLAY routine at the rate given in the header,
+//! while the APU output is sampled into a WAV file.
+
+use std::{fs, path::Path};
+
+use bytes::Buf;
+
+use crate::{State, rom::ROMError, cartridge::{Cartridge, NSFMapper, NSF_BANK_SIZE}, region::Region, wav};
+
+/// Sample rate of rendered audio
+pub const SAMPLE_RATE: u32 = 44_100;
+/// Give up on INIT / PLAY routines that don't return within this many cycles
+const MAX_CALL_CYCLES: usize = 1_000_000;
+
+#[derive(Debug, Clone, Default)]
+pub struct NSF {
+    /// Number of songs
+    pub songs: u8,
+    /// First song to play, 1-based
+    pub start_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    /// Period of PLAY calls in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    /// Initial values of the bank registers $5FF8-$5FFF, all zero if the tune does not bankswitch
+    pub banks: [u8; 8],
+    /// Tune is meant for PAL systems
+    pub pal: bool,
+    /// Program data, loaded at `load_addr`
+    pub data: Vec<u8>,
+}
+
+/// Read a zero terminated string
+fn read_str(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+impl NSF {
+    pub fn load(path: &Path) -> Result<Self, ROMError> {
+        Self::parse(&fs::read(path)?)
+    }
+    pub fn parse(file: &[u8]) -> Result<Self, ROMError> {
+        if file.starts_with(b"NESM\x1a") {
+            Self::parse_nsf(file)
+        } else if file.starts_with(b"NSFE") {
+            Self::parse_nsfe(&file[4..])
+        } else {
+            Err(ROMError::MalformedNSF("missing NESM or NSFE magic"))
+        }
+    }
+    fn parse_nsf(file: &[u8]) -> Result<Self, ROMError> {
+        if file.len() < 0x80 { return Err(ROMError::MalformedNSF("header is truncated")) }
+        let mut header = &file[5..0x80];
+        let _version = header.get_u8();
+        let songs = header.get_u8();
+        let start_song = header.get_u8();
+        let load_addr = header.get_u16_le();
+        let init_addr = header.get_u16_le();
+        let play_addr = header.get_u16_le();
+        let name = read_str(&header[..32]);
+        let artist = read_str(&header[32..64]);
+        let copyright = read_str(&header[64..96]);
+        header.advance(96);
+        let ntsc_speed = header.get_u16_le();
+        let mut banks = [0u8; 8];
+        header.copy_to_slice(&mut banks);
+        let pal_speed = header.get_u16_le();
+        // Bit 0: PAL, bit 1: dual PAL/NTSC
+        let region = header.get_u8();
+        Ok(Self {
+            songs, start_song, load_addr, init_addr, play_addr, name, artist, copyright,
+            ntsc_speed, pal_speed, banks,
+            pal: region & 0b11 == 0b01,
+            data: file[0x80..].to_vec(),
+        })
+    }
+    /// NSFe is a list of chunks: 4 byte length, 4 byte id, data
+    fn parse_nsfe(mut file: &[u8]) -> Result<Self, ROMError> {
+        let mut nsf = NSF { songs: 1, start_song: 1, ntsc_speed: 16639, pal_speed: 19997, ..Default::default() };
+        let mut has_info = false;
+        while file.remaining() >= 8 {
+            let len = file.get_u32_le() as usize;
+            let mut id = [0u8; 4];
+            file.copy_to_slice(&mut id);
+            if file.remaining() < len { return Err(ROMError::MalformedNSF("chunk is truncated")) }
+            let mut chunk = &file[..len];
+            file.advance(len);
+            match &id {
+                b"INFO" => {
+                    if chunk.remaining() < 9 { return Err(ROMError::MalformedNSF("INFO chunk is too short")) }
+                    nsf.load_addr = chunk.get_u16_le();
+                    nsf.init_addr = chunk.get_u16_le();
+                    nsf.play_addr = chunk.get_u16_le();
+                    nsf.pal = chunk.get_u8() & 0b11 == 0b01;
+                    let _expansion = chunk.get_u8();
+                    if chunk.has_remaining() { nsf.songs = chunk.get_u8(); }
+                    // Starting song is 0-based in NSFe
+                    if chunk.has_remaining() { nsf.start_song = chunk.get_u8() + 1; }
+                    has_info = true;
+                }
+                b"DATA" => nsf.data = chunk.to_vec(),
+                b"BANK" => for (bank, val) in nsf.banks.iter_mut().zip(chunk) { *bank = *val },
+                b"RATE" => {
+                    if chunk.remaining() >= 2 { nsf.ntsc_speed = chunk.get_u16_le(); }
+                    if chunk.remaining() >= 2 { nsf.pal_speed = chunk.get_u16_le(); }
+                }
+                b"auth" => {
+                    let mut strings = chunk.split(|b| *b == 0).map(read_str);
+                    nsf.name = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                }
+                b"NEND" => break,
+                // Chunks starting with a lowercase letter are optional and may be skipped
+                [first, ..] if first.is_ascii_lowercase() => {}
+                _ => return Err(ROMError::MalformedNSF("unknown required chunk")),
+            }
+        }
+        if !has_info { return Err(ROMError::MalformedNSF("missing INFO chunk")) }
+        Ok(nsf)
+    }
+
+    /// Tune uses bank switching
+    pub fn is_banked(&self) -> bool { self.banks.iter().any(|b| *b != 0) }
+
+    /// Put the tune into cartridge space and initialize memory as an NSF player would
+    pub fn install(&self, state: &mut State) -> Result<(), ROMError> {
+        let (padding, banks) = if self.is_banked() {
+            ((self.load_addr & 0x0FFF) as usize, self.banks)
+        } else if self.load_addr >= 0x8000 {
+            ((self.load_addr - 0x8000) as usize, [0, 1, 2, 3, 4, 5, 6, 7])
+        } else {
+            return Err(ROMError::MalformedNSF("load address is below $8000"))
+        };
+        let mut prg_rom = vec![0u8; padding];
+        prg_rom.extend_from_slice(&self.data);
+        let bank_count = prg_rom.len().div_ceil(NSF_BANK_SIZE).max(8);
+        prg_rom.resize(bank_count * NSF_BANK_SIZE, 0);
+
+        state.mem.cartridge = Cartridge {
+            mapper: Box::new(NSFMapper::new(banks, bank_count)),
+            prg_rom,
+            ..Default::default()
+        };
+        state.mem.ram = [0u8; 0x0800];
+        for addr in 0x4000..=0x4013 { state.mem.write(addr, 0x00); }
+        state.mem.write(0x4015, 0x0F);
+        state.mem.write(0x4017, 0x40);
+        Ok(())
+    }
+}
+
+/// Samples the APU output at the audio sample rate as CPU cycles pass
+struct Recorder {
+    samples: Vec<i16>,
+    cycles_per_sample: f64,
+    next_sample: f64,
+    /// Last input and output of the high pass filter
+    filter: (f32, f32),
+}
+impl Recorder {
+    fn catch_up(&mut self, state: &State) {
+        while state.cycle_count as f64 >= self.next_sample {
+            // The NES output has a high pass filter, which removes the DC offset of the mixer
+            let input = state.mem.apu.output();
+            self.filter.1 = 0.996 * (self.filter.1 + input - self.filter.0);
+            self.filter.0 = input;
+            self.samples.push((self.filter.1.clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+            self.next_sample += self.cycles_per_sample;
+        }
+    }
+}
+
+/// Run a subroutine of the tune until it returns
+fn call(state: &mut State, recorder: &mut Recorder, addr: u16) -> Result<(), ROMError> {
+    let start = state.cycle_count;
+    state.start_call(addr);
+    while state.in_call() {
+        if !state.step() || state.cycle_count - start > MAX_CALL_CYCLES {
+            return Err(ROMError::NSFRoutineFailed(addr))
+        }
+        recorder.catch_up(state);
+    }
+    Ok(())
+}
+
+/// Play a track of an NSF file for a number of seconds and write the audio to a WAV file
+pub fn render(path: &Path, track: Option<u8>, seconds: f64, out: &Path) -> Result<(), ROMError> {
+    let nsf = NSF::load(path)?;
+    let track = track.unwrap_or(nsf.start_song);
+    if track == 0 || track > nsf.songs { return Err(ROMError::InvalidTrack { track, songs: nsf.songs }) }
+    println!("Playing \"{}\" by {} ({}), track {track}/{}", nsf.name, nsf.artist, nsf.copyright, nsf.songs);
+
+    let (region, speed) = if nsf.pal { (Region::Pal, nsf.pal_speed) } else { (Region::Ntsc, nsf.ntsc_speed) };
+    let clock = region.cpu_clock();
+    let play_period = (clock * speed as f64 / 1_000_000.0) as usize;
+    let total_cycles = (seconds * clock) as usize;
+
+    let mut state = State::new();
+    state.trace = false;
+    state.set_region(region);
+    nsf.install(&mut state)?;
+    state.reset();
+    let mut recorder = Recorder {
+        samples: Vec::with_capacity((seconds * SAMPLE_RATE as f64) as usize),
+        cycles_per_sample: clock / SAMPLE_RATE as f64,
+        next_sample: state.cycle_count as f64,
+        filter: (0.0, 0.0),
+    };
+
+    // INIT is called with the song index in A and the region in X
+    state.cpu.a = track - 1;
+    state.cpu.x = nsf.pal as u8;
+    call(&mut state, &mut recorder, nsf.init_addr)?;
+
+    let mut next_play = state.cycle_count;
+    while state.cycle_count < total_cycles {
+        if state.cycle_count >= next_play {
+            next_play += play_period;
+            call(&mut state, &mut recorder, nsf.play_addr)?;
+        } else {
+            // CPU is idle until the next PLAY call, only the APU keeps running
+            state.idle();
+            recorder.catch_up(&state);
+        }
+    }
+    wav::write_wav(out, SAMPLE_RATE, &recorder.samples)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nsf_header() -> Vec<u8> {
+        let mut file = vec![0u8; 0x80];
+        file[..5].copy_from_slice(b"NESM\x1a");
+        // Version 1, 3 songs starting at 2, load $8000, init $8003, play $8006
+        file[5..14].copy_from_slice(&[1, 3, 2, 0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        file[0x0E..0x12].copy_from_slice(b"Tune");
+        file[0x2E..0x31].copy_from_slice(b"Bob");
+        file[0x4E..0x52].copy_from_slice(b"2026");
+        file[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        file[0x70..0x78].copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        file[0x78..0x7A].copy_from_slice(&19997u16.to_le_bytes());
+        file[0x7A] = 0b01;
+        file
+    }
+
+    #[test]
+    fn nsf() {
+        let file = [nsf_header(), vec![0xEA, 0x60]].concat();
+        let nsf = NSF::parse(&file).unwrap();
+        assert_eq!((nsf.songs, nsf.start_song), (3, 2));
+        assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8003, 0x8006));
+        assert_eq!((nsf.name.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()), ("Tune", "Bob", "2026"));
+        assert_eq!((nsf.ntsc_speed, nsf.pal_speed), (16639, 19997));
+        assert_eq!(nsf.banks, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(nsf.is_banked());
+        assert!(nsf.pal);
+        assert_eq!(nsf.data, [0xEA, 0x60]);
+        // Dual PAL/NTSC tunes are played as NTSC
+        let mut file = file;
+        file[0x7A] = 0b11;
+        assert!(!NSF::parse(&file).unwrap().pal);
+
+        assert!(matches!(NSF::parse(&nsf_header()[..0x7F]), Err(ROMError::MalformedNSF("header is truncated"))));
+        assert!(matches!(NSF::parse(b"NESN\x1a"), Err(ROMError::MalformedNSF("missing NESM or NSFE magic"))));
+    }
+
+    /// NSFe chunk: length, id, data
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u32).to_le_bytes()[..], id, data].concat()
+    }
+
+    #[test]
+    fn nsfe() {
+        let info = [0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0b01, 0, 5, 1];
+        let file = [
+            b"NSFE".to_vec(),
+            chunk(b"INFO", &info),
+            chunk(b"DATA", &[0xEA, 0x60]),
+            chunk(b"BANK", &[0, 1]),
+            chunk(b"RATE", &[0x10, 0x27]),
+            chunk(b"auth", b"Tune\0Bob\0\x32\x30\x32\x36\0Ripper\0"),
+            chunk(b"tlbl", b"skipped"),
+            chunk(b"NEND", &[]),
+            chunk(b"XXXX", &[]),
+        ].concat();
+        let nsf = NSF::parse(&file).unwrap();
+        // Starting song is 0-based in the INFO chunk
+        assert_eq!((nsf.songs, nsf.start_song), (5, 2));
+        assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8003, 0x8006));
+        assert!(nsf.pal);
+        assert_eq!(nsf.data, [0xEA, 0x60]);
+        assert_eq!(nsf.banks, [0, 1, 0, 0, 0, 0, 0, 0]);
+        // Only the NTSC rate was given
+        assert_eq!((nsf.ntsc_speed, nsf.pal_speed), (10000, 19997));
+        assert_eq!((nsf.name.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()), ("Tune", "Bob", "2026"));
+
+        // Starting song is optional
+        let nsf = NSF::parse(&[b"NSFE".to_vec(), chunk(b"INFO", &info[..9])].concat()).unwrap();
+        assert_eq!((nsf.songs, nsf.start_song), (5, 1));
+    }
+
+    #[test]
+    fn nsfe_errors() {
+        let error = |chunks: &[Vec<u8>]| NSF::parse(&[b"NSFE".to_vec(), chunks.concat()].concat()).unwrap_err();
+        let info = chunk(b"INFO", &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0, 0, 1]);
+        assert!(matches!(error(&[chunk(b"DATA", &[0xEA])]), ROMError::MalformedNSF("missing INFO chunk")));
+        assert!(matches!(error(&[chunk(b"INFO", &[0; 8])]), ROMError::MalformedNSF("INFO chunk is too short")));
+        assert!(matches!(error(&[info.clone(), chunk(b"BLOB", &[])]), ROMError::MalformedNSF("unknown required chunk")));
+        let mut truncated = chunk(b"DATA", &[0xEA, 0x60]);
+        truncated.pop();
+        assert!(matches!(error(&[info, truncated]), ROMError::MalformedNSF("chunk is truncated")));
+    }
+}
@@ -0,0 +1,88 @@
+//! NTSC composite video simulation, see: https://www.nesdev.org/wiki/NTSC_video
+//! The PPU does not output RGB but a square wave between two voltage levels, whose phase relative to the 3.58 MHz
+//! color subcarrier is the hue. Each pixel lasts 8 cycles of the 21.48 MHz master clock, and the subcarrier has a
+//! period of 12 master clocks, so the phase advances by 8 every pixel. Decoding the signal back to YIQ picks up the
+//! neighboring pixels, which gives the color fringing and the dot crawl of a real console.
+
+use std::f32::consts::PI;
+
+use crate::ppu::{WIDTH, HEIGHT};
+
+/// Width of the filtered picture, the signal has 8 samples per pixel which are decoded to this many pixels
+pub const NTSC_WIDTH: usize = 602;
+/// Signal samples per pixel
+const SAMPLES: usize = 8;
+/// Dots per scanline, which sets how far the subcarrier phase moves from one line to the next
+const DOTS: usize = 341;
+/// Dot of the first visible pixel
+const FIRST_DOT: usize = 1;
+
+/// Voltage levels of the signal, relative to sync: low levels for luma 0-3, then high levels for luma 0-3
+const LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+/// Signal level multiplier while an emphasized color phase is output
+const ATTENUATION: f32 = 0.746;
+/// Phase offset that aligns the decoder's hue with the PPU's
+const HUE: f32 = 3.9;
+const GAMMA: f32 = 1.8;
+
+/// The signal is high for 6 out of the 12 phases, shifted by the color's hue
+fn in_color_phase(color: u16, phase: usize) -> bool {
+    (color as usize + phase) % 12 < 6
+}
+
+/// Level of the composite signal for a pixel (emphasis bits 6-8, color index bits 0-5) at a subcarrier phase
+fn signal(pixel: u16, phase: usize) -> f32 {
+    let color = pixel & 0x0F;
+    // Colors $xE and $xF are black
+    let level = if color > 13 { 1 } else { (pixel >> 4 & 0b11) as usize };
+    let emphasis = pixel >> 6;
+    let mut low = LEVELS[level];
+    let mut high = LEVELS[4 + level];
+    // Hue 0 is the high level only (grey), hues 13-15 the low level only
+    if color == 0 { low = high; }
+    if color > 12 { high = low; }
+    let mut signal = if in_color_phase(color, phase) { high } else { low };
+    if (emphasis & 0b001 != 0 && in_color_phase(0, phase))
+        || (emphasis & 0b010 != 0 && in_color_phase(4, phase))
+        || (emphasis & 0b100 != 0 && in_color_phase(8, phase)) {
+        signal *= ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+fn gamma_fix(f: f32) -> f32 {
+    if f <= 0.0 { 0.0 } else { f.powf(2.2 / GAMMA) }
+}
+
+/// Encode a PPU framebuffer as a composite signal and decode it to RGB, `NTSC_WIDTH` by `HEIGHT` pixels.
+/// `frame_phase` is the PPU's dot phase at the start of the frame, the output only depends on it and the framebuffer.
+pub fn filter(framebuffer: &[u16], frame_phase: u8) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(NTSC_WIDTH * HEIGHT * 3);
+    let mut line = vec![0f32; WIDTH * SAMPLES];
+    for (y, pixels) in framebuffer.as_chunks::<WIDTH>().0.iter().enumerate() {
+        let start_phase = (frame_phase as usize + y * DOTS + FIRST_DOT) * SAMPLES;
+        for (i, level) in line.iter_mut().enumerate() {
+            *level = signal(pixels[i / SAMPLES], (start_phase + i) % 12);
+        }
+        for x in 0..NTSC_WIDTH {
+            // Average one subcarrier period (12 samples) around the output pixel
+            let center = x * line.len() / NTSC_WIDTH;
+            let (begin, end) = (center.saturating_sub(6), (center + 6).min(line.len()));
+            let (mut luma, mut i, mut q) = (0f32, 0f32, 0f32);
+            for (p, level) in line.iter().enumerate().take(end).skip(begin) {
+                let level = level / 12.0;
+                let angle = PI * ((start_phase + p) % 12) as f32 / 6.0 + PI * HUE / 6.0;
+                luma += level;
+                i += level * angle.cos();
+                q += level * angle.sin();
+            }
+            let r = luma + 0.946882 * i + 0.623557 * q;
+            let g = luma - 0.274788 * i - 0.635691 * q;
+            let b = luma - 1.108545 * i + 1.709007 * q;
+            rgb.extend([r, g, b].map(|c| (255.95 * gamma_fix(c)).clamp(0.0, 255.0) as u8));
+        }
+    }
+    rgb
+}
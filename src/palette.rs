@@ -0,0 +1,94 @@
+//! Conversion of the PPU's color indices to RGB, see: https://www.nesdev.org/wiki/PPU_palettes
+//! The PPU outputs a 6 bit color index along with the 3 emphasis bits of PPUMASK, there are no RGB values on the
+//! console itself. `.pal` files list the RGB value of each of the 64 colors, optionally followed by the colors for the
+//! other 7 combinations of emphasis bits.
+
+use std::{fs, io, path::Path};
+
+use thiserror::Error;
+
+/// Number of colors the PPU can output, not counting emphasis
+pub const COLORS: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum PaletteError {
+    #[error("unable to read palette file")]
+    IOError(#[from] io::Error),
+    #[error("palette file is {0} bytes, expected 192 (64 colors) or 1536 (64 colors with 8 emphasis combinations)")]
+    InvalidSize(usize),
+}
+
+/// RGB values of the 2C02, as listed on the nesdev wiki
+const DEFAULT_2C02: [[u8; 3]; COLORS] = [
+    [84, 84, 84], [0, 30, 116], [8, 16, 144], [48, 0, 136], [68, 0, 100], [92, 0, 48], [84, 4, 0], [60, 24, 0],
+    [32, 42, 0], [8, 58, 0], [0, 64, 0], [0, 60, 0], [0, 50, 60], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [152, 150, 152], [8, 76, 196], [48, 50, 236], [92, 30, 228], [136, 20, 176], [160, 20, 100], [152, 34, 32], [120, 60, 0],
+    [84, 90, 0], [40, 114, 0], [8, 124, 0], [0, 118, 40], [0, 102, 120], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [76, 154, 236], [120, 124, 236], [176, 98, 236], [228, 84, 236], [236, 88, 180], [236, 106, 100], [212, 136, 32],
+    [160, 170, 0], [116, 196, 0], [76, 208, 32], [56, 204, 108], [56, 180, 204], [60, 60, 60], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236], [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180], [160, 214, 228], [160, 162, 160], [0, 0, 0], [0, 0, 0],
+];
+
+/// Brightness of the color channels that are not emphasized
+const ATTENUATION: f32 = 0.816328;
+
+/// RGB value of every color index under every combination of emphasis bits
+#[derive(Debug, Clone)]
+pub struct Palette {
+    /// Indexed by `emphasis << 6 | color`
+    colors: Box<[[u8; 3]; COLORS * 8]>,
+    /// PAL and Dendy PPUs swap the red and green emphasis bits of PPUMASK
+    swap_red_green: bool,
+}
+impl Default for Palette {
+    fn default() -> Self { Self::from_colors(&DEFAULT_2C02) }
+}
+impl Palette {
+    pub fn load(path: &Path) -> Result<Self, PaletteError> {
+        Self::parse(&fs::read(path)?)
+    }
+    pub fn parse(file: &[u8]) -> Result<Self, PaletteError> {
+        let mut colors = Box::new([[0u8; 3]; COLORS * 8]);
+        if file.len() != COLORS * 3 && file.len() != colors.len() * 3 {
+            return Err(PaletteError::InvalidSize(file.len()))
+        }
+        for (color, rgb) in colors.iter_mut().zip(file.as_chunks::<3>().0) {
+            color.copy_from_slice(rgb);
+        }
+        if file.len() == COLORS * 3 {
+            return Ok(Self::from_colors(colors[..COLORS].try_into().unwrap()))
+        }
+        Ok(Self { colors, swap_red_green: false })
+    }
+    /// Use the PAL / Dendy meaning of the emphasis bits
+    pub fn with_swapped_emphasis(self, swap_red_green: bool) -> Self {
+        Self { swap_red_green, ..self }
+    }
+    /// Derive the emphasized variants by dimming the channels that are not emphasized
+    fn from_colors(base: &[[u8; 3]; COLORS]) -> Self {
+        let mut colors = Box::new([[0u8; 3]; COLORS * 8]);
+        for emphasis in 0..8 {
+            for (i, rgb) in base.iter().enumerate() {
+                let mut out = *rgb;
+                // Bit 0: red, bit 1: green, bit 2: blue. Emphasizing a channel dims the other two.
+                for (channel, value) in out.iter_mut().enumerate() {
+                    let dimmed = (0..3).any(|bit| bit != channel && emphasis & (1 << bit) != 0);
+                    if dimmed { *value = (*value as f32 * ATTENUATION) as u8; }
+                }
+                colors[emphasis << 6 | i] = out;
+            }
+        }
+        Self { colors, swap_red_green: false }
+    }
+    /// RGB value of a pixel from the PPU framebuffer (emphasis bits 6-8, color index bits 0-5)
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        let mut index = pixel as usize & (COLORS * 8 - 1);
+        if self.swap_red_green {
+            let red = index >> 6 & 1;
+            let green = index >> 7 & 1;
+            index = (index & !0b11_000000) | red << 7 | green << 6;
+        }
+        self.colors[index]
+    }
+}
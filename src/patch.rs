@@ -0,0 +1,316 @@
+//! ROM patch formats, applied to the ROM image in memory before it is parsed.
+//! - IPS: https://zerosoft.zophar.net/ips.php
+//! - UPS: https://www.romhacking.net/documents/392/
+//! - BPS: https://www.romhacking.net/documents/746/
+
+use bytes::Buf;
+
+use crate::rom::ROMError;
+
+/// Largest patched image accepted from a UPS / BPS header, far above any NES or FDS image.
+/// The size is checked before allocating so a bad header can't exhaust memory.
+const MAX_TARGET_SIZE: usize = 16 * 1024 * 1024;
+/// Bytes needed to encode any 64-bit number as a varint
+const MAX_VARINT_BYTES: usize = 10;
+
+/// Apply a patch to a ROM image, the format is detected from the patch's magic bytes
+pub fn apply(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, ROMError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, &patch[5..])
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err(ROMError::UnknownPatchFormat(patch.iter().take(4).copied().collect()))
+    }
+}
+
+/// Make sure enough bytes are left in the patch before reading from it
+fn need(patch: &[u8], len: usize) -> Result<(), ROMError> {
+    if patch.remaining() < len { Err(ROMError::MalformedPatch("unexpected end of patch")) } else { Ok(()) }
+}
+
+/// Records of 24-bit offset, 16-bit size, and data. A size of 0 is a run of one repeated byte.
+/// Optionally followed by a 24-bit length to truncate the ROM to after the `EOF` marker.
+fn apply_ips(mut rom: Vec<u8>, mut patch: &[u8]) -> Result<Vec<u8>, ROMError> {
+    loop {
+        need(patch, 3)?;
+        if patch.starts_with(b"EOF") {
+            patch.advance(3);
+            if patch.remaining() >= 3 { rom.truncate(patch.get_uint(3) as usize); }
+            return Ok(rom)
+        }
+        let offset = patch.get_uint(3) as usize;
+        need(patch, 2)?;
+        let size = patch.get_u16() as usize;
+        let (len, data) = if size == 0 {
+            need(patch, 3)?;
+            let len = patch.get_u16() as usize;
+            (len, vec![patch.get_u8(); len])
+        } else {
+            need(patch, size)?;
+            let data = patch[..size].to_vec();
+            patch.advance(size);
+            (size, data)
+        };
+        if rom.len() < offset + len { rom.resize(offset + len, 0); }
+        rom[offset..offset + len].copy_from_slice(&data);
+    }
+}
+
+/// Variable length number used by UPS and BPS, each byte holds 7 bits and the high bit marks the last byte
+fn read_varint(patch: &mut &[u8]) -> Result<usize, ROMError> {
+    let too_large = || ROMError::MalformedPatch("number too large");
+    let mut value = 0usize;
+    let mut shift = 1usize;
+    for _ in 0..MAX_VARINT_BYTES {
+        need(patch, 1)?;
+        let byte = patch.get_u8();
+        let bits = ((byte & 0x7F) as usize).checked_mul(shift).ok_or_else(too_large)?;
+        value = value.checked_add(bits).ok_or_else(too_large)?;
+        if byte & 0x80 != 0 { return Ok(value) }
+        shift = shift.checked_mul(0x80).ok_or_else(too_large)?;
+        value = value.checked_add(shift).ok_or_else(too_large)?;
+    }
+    Err(too_large())
+}
+
+/// Target size from a UPS / BPS header, rejected if it is too large to be a ROM
+fn read_target_size(patch: &mut &[u8]) -> Result<usize, ROMError> {
+    let size = read_varint(patch)?;
+    if size > MAX_TARGET_SIZE { return Err(ROMError::MalformedPatch("target size too large")) }
+    Ok(size)
+}
+
+/// Split off the 12 byte footer of UPS / BPS patches, checking the CRC32 of the source and the patch itself.
+/// Returns the patch body (without magic) and the expected CRC32 of the target.
+fn check_footer<'a>(rom: &[u8], patch: &'a [u8]) -> Result<(&'a [u8], u32), ROMError> {
+    if patch.len() < 4 + 12 { return Err(ROMError::MalformedPatch("patch too short")) }
+    let (body, mut footer) = patch.split_at(patch.len() - 12);
+    let source_crc = footer.get_u32_le();
+    let target_crc = footer.get_u32_le();
+    let patch_crc = footer.get_u32_le();
+    let found = crc32fast::hash(&patch[..patch.len() - 4]);
+    if found != patch_crc { return Err(ROMError::PatchChecksum { which: "patch", expected: patch_crc, found }) }
+    let found = crc32fast::hash(rom);
+    if found != source_crc { return Err(ROMError::PatchChecksum { which: "source ROM", expected: source_crc, found }) }
+    Ok((&body[4..], target_crc))
+}
+fn check_target(target: Vec<u8>, expected: u32) -> Result<Vec<u8>, ROMError> {
+    let found = crc32fast::hash(&target);
+    if found != expected { return Err(ROMError::PatchChecksum { which: "patched ROM", expected, found }) }
+    Ok(target)
+}
+
+/// Runs of bytes XORed into the source, each run starting at a relative offset and terminated by a zero byte
+fn apply_ups(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, ROMError> {
+    let (mut body, target_crc) = check_footer(&rom, patch)?;
+    let source_size = read_varint(&mut body)?;
+    let target_size = read_target_size(&mut body)?;
+    if source_size != rom.len() {
+        return Err(ROMError::PatchSizeMismatch { which: "source ROM", expected: source_size, found: rom.len() })
+    }
+    let mut target = rom;
+    target.resize(target_size, 0);
+    let mut pos = 0usize;
+    while body.has_remaining() {
+        pos = pos.checked_add(read_varint(&mut body)?).ok_or(ROMError::MalformedPatch("number too large"))?;
+        loop {
+            need(body, 1)?;
+            let xor = body.get_u8();
+            // Terminating zero also advances the position
+            if xor == 0 { pos = pos.saturating_add(1); break }
+            if let Some(byte) = target.get_mut(pos) { *byte ^= xor; }
+            pos = pos.saturating_add(1);
+        }
+    }
+    check_target(target, target_crc)
+}
+
+/// Target is built from a list of commands copying from the source, the patch, or earlier parts of the target
+fn apply_bps(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, ROMError> {
+    let (mut body, target_crc) = check_footer(&rom, patch)?;
+    let source_size = read_varint(&mut body)?;
+    let target_size = read_target_size(&mut body)?;
+    if source_size != rom.len() {
+        return Err(ROMError::PatchSizeMismatch { which: "source ROM", expected: source_size, found: rom.len() })
+    }
+    let metadata_size = read_varint(&mut body)?;
+    need(body, metadata_size)?;
+    body.advance(metadata_size);
+
+    let out_of_range = || ROMError::MalformedPatch("copy outside of source or target");
+    let past_end = || ROMError::MalformedPatch("patch writes past end of target");
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+    // Offsets are stored as sign-magnitude with the sign in the lowest bit
+    let read_relative = |body: &mut &[u8]| -> Result<isize, ROMError> {
+        let data = read_varint(body)?;
+        let magnitude = (data >> 1) as isize;
+        Ok(if data & 1 != 0 { -magnitude } else { magnitude })
+    };
+    while body.has_remaining() {
+        let data = read_varint(&mut body)?;
+        let len = (data >> 2) + 1;
+        // Checked up front, a command could otherwise copy far more than the target holds
+        if len > target_size - target.len() { return Err(past_end()) }
+        match data & 3 {
+            // SourceRead: copy from the same offset in the source
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(rom.get(start..start + len).ok_or_else(out_of_range)?);
+            }
+            // TargetRead: copy bytes from the patch
+            1 => {
+                need(body, len)?;
+                target.extend_from_slice(&body[..len]);
+                body.advance(len);
+            }
+            // SourceCopy: copy from anywhere in the source
+            2 => {
+                source_offset = source_offset.checked_add(read_relative(&mut body)?).ok_or_else(out_of_range)?;
+                let start = usize::try_from(source_offset).map_err(|_| out_of_range())?;
+                target.extend_from_slice(rom.get(start..start + len).ok_or_else(out_of_range)?);
+                source_offset += len as isize;
+            }
+            // TargetCopy: copy from the already written target, byte by byte as the ranges may overlap
+            _ => {
+                target_offset = target_offset.checked_add(read_relative(&mut body)?).ok_or_else(out_of_range)?;
+                for _ in 0..len {
+                    let byte = usize::try_from(target_offset).ok().and_then(|i| target.get(i)).ok_or_else(out_of_range)?;
+                    target.push(*byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(ROMError::PatchSizeMismatch { which: "patched ROM", expected: target_size, found: target.len() })
+    }
+    check_target(target, target_crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a number the way `read_varint` decodes it
+    fn varint(mut n: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let byte = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte | 0x80);
+                return out
+            }
+            out.push(byte);
+            n -= 1;
+        }
+    }
+
+    /// UPS / BPS patch: magic, body, then the CRC32s of source, target and the patch itself
+    fn with_footer(magic: &[u8], body: &[u8], source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = [magic, body].concat();
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(crc32fast::hash(target).to_le_bytes());
+        patch.extend(crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn varints() {
+        for n in [0, 1, 0x7F, 0x80, 0x407F, 0x4080, 123_456_789] {
+            assert_eq!(read_varint(&mut varint(n).as_slice()).unwrap(), n);
+        }
+        assert!(matches!(read_varint(&mut [0x00].as_slice()), Err(ROMError::MalformedPatch("unexpected end of patch"))));
+        assert_eq!(read_varint(&mut varint(usize::MAX).as_slice()).unwrap(), usize::MAX);
+        // One past the largest number, and continuation bytes going on past 10 bytes
+        let mut past_max = varint(usize::MAX);
+        *past_max.last_mut().unwrap() += 1;
+        assert!(matches!(read_varint(&mut past_max.as_slice()), Err(ROMError::MalformedPatch("number too large"))));
+        let too_long = [vec![0x7F; 10], vec![0x80]].concat();
+        assert!(matches!(read_varint(&mut too_long.as_slice()), Err(ROMError::MalformedPatch("number too large"))));
+    }
+
+    #[test]
+    fn huge_target_size() {
+        let source = vec![0; 4];
+        for magic in [b"UPS1", b"BPS1"] {
+            // Valid CRCs, but a target size no ROM has
+            let body = [varint(4), varint(usize::MAX >> 1), varint(0)].concat();
+            let patch = with_footer(magic, &body, &source, &source);
+            assert!(matches!(apply(source.clone(), &patch), Err(ROMError::MalformedPatch("target size too large"))));
+        }
+        // A BPS command copying more than the target holds is rejected before it runs
+        let body = [varint(4), varint(4), varint(0), varint(usize::MAX >> 2 << 2 | 3), varint(0)].concat();
+        let patch = with_footer(b"BPS1", &body, &source, &source);
+        assert!(matches!(apply(source, &patch), Err(ROMError::MalformedPatch("patch writes past end of target"))));
+    }
+
+    #[test]
+    fn ips() {
+        let patch = b"PATCH\x00\x00\x01\x00\x02\xAA\xBB\x00\x00\x06\x00\x00\x00\x03\xCC\x00\x00\x00\x00\x01\x11EOF";
+        // Two bytes at 1, a run of 3 past the end which grows the ROM, then one byte at offset 0
+        assert_eq!(apply(vec![0; 4], patch).unwrap(), [0x11, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC]);
+        // Truncation length after the EOF marker
+        let patch = b"PATCH\x00\x00\x05\x00\x01\x55EOF\x00\x00\x02";
+        assert_eq!(apply(vec![1, 2, 3], patch).unwrap(), [1, 2]);
+        let patch = b"PATCH\x00\x00\x01\x00\x04\xAA\xBB";
+        assert!(matches!(apply(vec![0; 4], patch), Err(ROMError::MalformedPatch("unexpected end of patch"))));
+        assert!(matches!(apply(vec![0; 4], b"PATCH\x00\x00\x01"), Err(ROMError::MalformedPatch(_))));
+    }
+
+    #[test]
+    fn ups() {
+        let source = vec![1, 2, 3, 4];
+        let target = vec![1, 2, 0x13, 4, 0, 0x77];
+        // XOR $10 into offset 2, skip the terminator's byte at 3, then XOR $77 into offset 5
+        let body = [varint(4), varint(6), varint(2), vec![0x10, 0x00], varint(1), vec![0x77, 0x00]].concat();
+        let patch = with_footer(b"UPS1", &body, &source, &target);
+        assert_eq!(apply(source.clone(), &patch).unwrap(), target);
+
+        assert!(matches!(apply(vec![9, 9, 9, 9], &patch), Err(ROMError::PatchChecksum { which: "source ROM", .. })));
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 1;
+        assert!(matches!(apply(source.clone(), &corrupt), Err(ROMError::PatchChecksum { which: "patch", .. })));
+        let wrong_size = with_footer(b"UPS1", &[varint(5), varint(6)].concat(), &source, &target);
+        assert!(matches!(apply(source, &wrong_size), Err(ROMError::PatchSizeMismatch { which: "source ROM", expected: 5, found: 4 })));
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"ABCDEFGH".to_vec();
+        let target = b"ABxyFGFGFGB".to_vec();
+        let body = [
+            varint(8), varint(11), varint(0),
+            // SourceRead "AB"
+            varint((2 - 1) << 2),
+            // TargetRead "xy"
+            varint((2 - 1) << 2 | 1), b"xy".to_vec(),
+            // SourceCopy "FG" from offset +5
+            varint((2 - 1) << 2 | 2), varint(5 << 1),
+            // TargetCopy 4 bytes from offset +4, overlapping what it writes: "FGFG"
+            varint((4 - 1) << 2 | 3), varint(4 << 1),
+            // SourceCopy "B" from offset -6
+            varint(2), varint(6 << 1 | 1),
+        ].concat();
+        let patch = with_footer(b"BPS1", &body, &source, &target);
+        assert_eq!(apply(source.clone(), &patch).unwrap(), target);
+
+        let past_source = [varint(8), varint(4), varint(0), varint((2 - 1) << 2 | 2), varint(7 << 1)].concat();
+        let patch = with_footer(b"BPS1", &past_source, &source, &target);
+        assert!(matches!(apply(source.clone(), &patch), Err(ROMError::MalformedPatch("copy outside of source or target"))));
+        let short = [varint(8), varint(4), varint(0), varint((2 - 1) << 2)].concat();
+        let patch = with_footer(b"BPS1", &short, &source, &target);
+        assert!(matches!(apply(source, &patch), Err(ROMError::PatchSizeMismatch { which: "patched ROM", expected: 4, found: 2 })));
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(matches!(apply(vec![], b"NES\x1A"), Err(ROMError::UnknownPatchFormat(magic)) if magic == b"NES\x1A"));
+        assert!(matches!(apply(vec![], b"UPS1"), Err(ROMError::MalformedPatch("patch too short"))));
+    }
+}
@@ -0,0 +1,691 @@
+//! Picture Processing Unit, see: https://www.nesdev.org/wiki/PPU
+//! The CPU talks to the PPU through 8 registers at $2000-$2007 (mirrored up to $3FFF), which in turn access the PPU's
+//! own 14-bit address space: pattern tables from the cartridge, nametables and palette RAM.
+//! Rendering is emulated dot by dot, see: https://www.nesdev.org/wiki/PPU_rendering
+
+use crate::{cartridge::{Cartridge, Mirroring}, region::Region};
+
+/// Size of the visible picture
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+/// Dots per scanline, the number of scanlines depends on the region
+const DOTS: u16 = 341;
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct PPUCtrl: u8 {
+        /// Generate an NMI (Non-Maskable Interrupt) at the start of the vertical blanking interval (0: off; 1: on)
+        const VBlankNMI      = 0b1000_0000;
+        /// (0: read backdrop from EXT pins; 1: output color on EXT pins)
+        const EXTCtrlSelect  = 0b0100_0000;
+        /// Sprite size (0: 8x8 pixels; 1: 8x16 pixels – see PPU OAM#Byte 1)
+        const SpriteSize     = 0b0010_0000;
+        /// Background Pattern Table Addr: 0=$0000, 1=$1000
+        const BgPatTblAddr   = 0b0001_0000;
+        /// Sprite pattern table address for 8x8 sprites (0: $0000; 1: $1000; ignored in 8x16 mode)
+        const PatTblAddrType = 0b0000_1000;
+        /// VRAM address increment per CPU read/write of PPUDATA (0: add 1, going across; 1: add 32, going down)
+        const VRAMAddrInc    = 0b0000_0100;
+        /// Base Nametable Addr: 0=$2000, 1=$2400, 2=$2800, 3=$2C00
+        const BaseNameAddr = 0b0000_0011;
+    }
+}
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct PPUMask: u8 {
+        /// Emphasize blue
+        const EmphasizeBlue = 0b1000_0000;
+        /// Emphasize green (red on PAL/Dendy)
+        const EmphasizeGreen = 0b0100_0000;
+        /// Emphasize red (green on PAL/Dendy)
+        const EmphasizeRed = 0b0010_0000;
+        /// Show sprites (0: hide, 1: show)
+        const SpritesShow = 0b0001_0000;
+        /// Show background (0: hide, 1: show)
+        const BgShow = 0b0000_1000;
+        /// Show sprites in leftmost 8 pixels of screen (0: hide, 1: show)
+        const SpritesLeftmostShow = 0b0000_0100;
+        /// Show background in leftmost 8 pixels of screen (0: hide, 1: show)
+        const BgLeftmostShow = 0b0000_0010;
+        /// Produce a greyscale display (0: normal color, 1: greyscale)
+        const Greyscale = 0b0000_0001;
+    }
+}
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct PPUStatus: u8 {
+        /// Vertical blank has started (0: not in vblank; 1: in vblank)
+        const VBlank         = 0b1000_0000;
+        /// Opaque pixel of sprite 0 overlapped an opaque background pixel
+        const Sprite0Hit     = 0b0100_0000;
+        /// More than 8 sprites were found on a scanline (with the hardware's false positives and negatives)
+        const SpriteOverflow = 0b0010_0000;
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+/// Manages the I/O state of the PPU: the registers at $2000-$2007 and the internal latches behind them.
+/// See: https://www.nesdev.org/wiki/PPU_registers and https://www.nesdev.org/wiki/PPU_scrolling
+pub struct PPUIO {
+    /// Control flags
+    pub ctrl: PPUCtrl,
+    /// Mask flags
+    pub mask: PPUMask,
+    /// Status flags
+    pub status: PPUStatus,
+    pub oam_addr: u8,
+    /// Current VRAM address, 15 bits: `yyy NN YYYYY XXXXX` (fine Y, nametable, coarse Y, coarse X)
+    pub v: u16,
+    /// Temporary VRAM address, the address of the top left onscreen tile
+    pub t: u16,
+    /// Fine X scroll, 3 bits
+    pub x: u8,
+    /// Write toggle shared by $2005 and $2006 (false: first write, true: second write)
+    pub w: bool,
+    /// PPUDATA read buffer
+    pub read_buffer: u8,
+    /// Last value on the PPU's data bus, read back from write-only registers
+    pub open_bus: u8,
+}
+
+/// Background tile fetches and the shift registers they are loaded into
+#[derive(Default, Debug, Clone)]
+struct Background {
+    /// Latches filled over 8 dots by the memory fetches
+    nametable: u8,
+    attribute: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+    /// Pattern bits of the current (high byte) and next (low byte) tile
+    shift_pattern_low: u16,
+    shift_pattern_high: u16,
+    /// Palette bits, expanded to 8 pixels per tile like the pattern
+    shift_attr_low: u16,
+    shift_attr_high: u16,
+}
+
+/// Sprite loaded for the current scanline
+#[derive(Default, Debug, Clone, Copy)]
+struct Sprite {
+    x: u8,
+    /// Byte 2 of the OAM entry: palette, priority and flip bits
+    attr: u8,
+    /// Pattern of the sprite's row, already flipped horizontally
+    pattern_low: u8,
+    pattern_high: u8,
+}
+/// OAM attribute byte bits
+pub const SPRITE_BEHIND_BG: u8 = 0b0010_0000;
+pub const SPRITE_FLIP_H: u8 = 0b0100_0000;
+pub const SPRITE_FLIP_V: u8 = 0b1000_0000;
+
+#[derive(Debug, Clone)]
+pub struct PPU {
+    pub io: PPUIO,
+    /// 2KB of nametable RAM (CIRAM), followed by the 2KB that four-screen cartridges add
+    pub vram: [u8; 0x1000],
+    /// Palette RAM, 6 bit color indices
+    pub palette: [u8; 0x20],
+    /// Object Attribute Memory, 64 sprites of 4 bytes
+    pub oam: [u8; 0x0100],
+    /// Current dot (0-340) and scanline (0-261, 0-311 on PAL / Dendy)
+    pub dot: u16,
+    pub scanline: u16,
+    /// Sets the number of scanlines and when vblank starts
+    pub region: Region,
+    /// Number of completed frames, incremented once the last visible scanline has been drawn
+    pub frame_count: u64,
+    /// Toggled at the end of every pre-render line, the NTSC pre-render line is one dot shorter on odd frames
+    odd_frame: bool,
+    /// PPU dots since power on, modulo 3. The NTSC color subcarrier repeats every 3 dots.
+    dot_phase: u8,
+    /// `dot_phase` at the start of the picture in the framebuffer, which makes the dot crawl pattern move between frames
+    pub frame_phase: u8,
+    /// Rising edge on the NMI output (vblank flag and `PPUCtrl::VBlankNMI`), cleared once the CPU takes the NMI
+    pub nmi: bool,
+    /// PPUSTATUS was read just before vblank starts, the flag is not set this frame
+    suppress_vblank: bool,
+    bg: Background,
+    /// Sprites found on the current scanline for the next one, 8 entries of 4 bytes
+    secondary_oam: [u8; 0x20],
+    secondary_count: usize,
+    /// Secondary OAM starts with sprite 0
+    secondary_zero: bool,
+    /// Sprites being drawn on the current scanline
+    sprites: [Sprite; 8],
+    sprite_count: usize,
+    sprite_zero: bool,
+    /// Pixels of the picture row by row: color index (bits 0-5) and PPUMASK emphasis bits (bits 6-8)
+    pub framebuffer: Box<[u16; WIDTH * HEIGHT]>,
+}
+impl Default for PPU {
+    fn default() -> Self {
+        Self {
+            io: PPUIO::default(), vram: [0u8; 0x1000], palette: [0u8; 0x20], oam: [0u8; 0x0100],
+            dot: 0, scanline: 0, region: Region::default(), frame_count: 0, odd_frame: false, dot_phase: 0, frame_phase: 0, nmi: false, suppress_vblank: false,
+            bg: Background::default(),
+            secondary_oam: [0xFF; 0x20], secondary_count: 0, secondary_zero: false,
+            sprites: [Sprite::default(); 8], sprite_count: 0, sprite_zero: false,
+            framebuffer: Box::new([0u16; WIDTH * HEIGHT]),
+        }
+    }
+}
+
+/// Index into nametable RAM of a nametable address ($2000-$3EFF)
+fn nametable_index(addr: u16, mirroring: Mirroring) -> usize {
+    let table = (addr >> 10) & 0b11;
+    let page = match mirroring {
+        Mirroring::Horizontal => table >> 1,
+        Mirroring::Vertical => table & 0b01,
+        Mirroring::SingleScreenA => 0,
+        Mirroring::SingleScreenB => 1,
+        Mirroring::FourScreen => table,
+    };
+    (page as usize * 0x400) | (addr & 0x3FF) as usize
+}
+/// Index into palette RAM of a palette address ($3F00-$3FFF).
+/// Entry 0 of each sprite palette ($3F10/$3F14/$3F18/$3F1C) is shared with the background palette.
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1F) as usize;
+    if index & 0x13 == 0x10 { index & 0x0F } else { index }
+}
+
+impl PPU {
+    /// Read from the PPU address space
+    pub fn read_vram(&self, cart: &Cartridge, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => cart.read_chr(addr),
+            0x2000..=0x3EFF => self.vram[nametable_index(addr, cart.mirroring())],
+            _ => {
+                let color = self.palette[palette_index(addr)] & 0x3F;
+                if self.io.mask.contains(PPUMask::Greyscale) { color & 0x30 } else { color }
+            }
+        }
+    }
+    /// Write to the PPU address space
+    pub fn write_vram(&mut self, cart: &mut Cartridge, addr: u16, val: u8) {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => cart.write_chr(addr, val),
+            0x2000..=0x3EFF => self.vram[nametable_index(addr, cart.mirroring())] = val,
+            _ => self.palette[palette_index(addr)] = val & 0x3F,
+        }
+    }
+    /// Step v after a PPUDATA access
+    fn increment_v(&mut self) {
+        let inc = if self.io.ctrl.contains(PPUCtrl::VRAMAddrInc) { 32 } else { 1 };
+        self.io.v = self.io.v.wrapping_add(inc) & 0x7FFF;
+    }
+
+    /// Handle CPU read from $2000-$3FFF
+    pub fn read_register(&mut self, addr: u16, cart: &mut Cartridge) -> u8 {
+        let out = match addr & 0x7 {
+            // PPUSTATUS: low bits are open bus, reading clears vblank and the write toggle
+            2 => {
+                // Reading right before vblank starts keeps the flag from being set, reading on the dot it is set
+                // or the one after still returns it, but both cancel the NMI. `dot` is the next dot to run.
+                if self.scanline == self.vblank_line() {
+                    match self.dot {
+                        1 => self.suppress_vblank = true,
+                        2..=3 => self.nmi = false,
+                        _ => {}
+                    }
+                }
+                let out = self.io.status.bits() | (self.io.open_bus & 0x1F);
+                self.io.status.remove(PPUStatus::VBlank);
+                self.io.w = false;
+                out
+            }
+            // OAMDATA: reads $FF while secondary OAM is being cleared
+            4 if self.rendering_enabled() && self.scanline < HEIGHT as u16 && (1..=64).contains(&self.dot) => 0xFF,
+            4 => self.oam[self.io.oam_addr as usize],
+            // PPUDATA: reads are delayed by a buffer, except for palette reads which still refill the buffer
+            // with the nametable byte "underneath" the palette
+            7 => {
+                let v = self.io.v & 0x3FFF;
+                let out = if v >= 0x3F00 {
+                    self.io.read_buffer = self.read_vram(cart, v - 0x1000);
+                    self.read_vram(cart, v) | (self.io.open_bus & 0xC0)
+                } else {
+                    let val = self.read_vram(cart, v);
+                    std::mem::replace(&mut self.io.read_buffer, val)
+                };
+                self.increment_v();
+                out
+            }
+            // Write only registers
+            _ => self.io.open_bus,
+        };
+        self.io.open_bus = out;
+        out
+    }
+    /// Handle CPU write to $2000-$3FFF
+    pub fn write_register(&mut self, addr: u16, val: u8, cart: &mut Cartridge) {
+        self.io.open_bus = val;
+        match addr & 0x7 {
+            // PPUCTRL: t: ...GH.. ........ <- d: ......GH
+            0 => {
+                let was_enabled = self.io.ctrl.contains(PPUCtrl::VBlankNMI);
+                self.io.ctrl = PPUCtrl::from_bits_retain(val);
+                let enabled = self.io.ctrl.contains(PPUCtrl::VBlankNMI);
+                // Enabling NMI during vblank raises it right away, disabling it as vblank starts cancels it
+                if enabled && !was_enabled && self.io.status.contains(PPUStatus::VBlank) {
+                    self.nmi = true;
+                } else if !enabled && self.scanline == self.vblank_line() && (2..=3).contains(&self.dot) {
+                    self.nmi = false;
+                }
+                self.io.t = (self.io.t & !0x0C00) | ((val as u16 & 0b11) << 10);
+            }
+            1 => self.io.mask = PPUMask::from_bits_retain(val),
+            3 => self.io.oam_addr = val,
+            4 => {
+                self.oam[self.io.oam_addr as usize] = val;
+                self.io.oam_addr = self.io.oam_addr.wrapping_add(1);
+            }
+            // PPUSCROLL
+            5 => {
+                if !self.io.w {
+                    // t: ....... ...ABCDE <- d: ABCDE...
+                    // x:              FGH <- d: .....FGH
+                    self.io.t = (self.io.t & !0x001F) | (val as u16 >> 3);
+                    self.io.x = val & 0b111;
+                } else {
+                    // t: FGH..AB CDE..... <- d: ABCDEFGH
+                    self.io.t = (self.io.t & !0x73E0) | ((val as u16 & 0b111) << 12) | ((val as u16 & 0xF8) << 2);
+                }
+                self.io.w = !self.io.w;
+            }
+            // PPUADDR
+            6 => {
+                if !self.io.w {
+                    // t: .CDEFGH ........ <- d: ..CDEFGH, bit 14 is cleared
+                    self.io.t = (self.io.t & 0x00FF) | ((val as u16 & 0x3F) << 8);
+                } else {
+                    // t: ....... ABCDEFGH <- d: ABCDEFGH, then v = t
+                    self.io.t = (self.io.t & 0xFF00) | val as u16;
+                    self.io.v = self.io.t;
+                }
+                self.io.w = !self.io.w;
+            }
+            7 => {
+                self.write_vram(cart, self.io.v, val);
+                self.increment_v();
+            }
+            // PPUSTATUS is read only
+            _ => {}
+        }
+    }
+
+    /// Background or sprite rendering is turned on, which makes the PPU fetch memory and update v
+    pub fn rendering_enabled(&self) -> bool {
+        self.io.mask.intersects(PPUMask::BgShow | PPUMask::SpritesShow)
+    }
+    /// Move v to the next tile, wrapping into the horizontally adjacent nametable
+    fn increment_x(&mut self) {
+        if self.io.v & 0x001F == 31 {
+            self.io.v = (self.io.v & !0x001F) ^ 0x0400;
+        } else {
+            self.io.v += 1;
+        }
+    }
+    /// Move v to the next pixel row, wrapping into the vertically adjacent nametable after row 29
+    fn increment_y(&mut self) {
+        if self.io.v & 0x7000 != 0x7000 {
+            self.io.v += 0x1000;
+            return
+        }
+        self.io.v &= !0x7000;
+        let mut coarse_y = (self.io.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.io.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // Out of bounds Y (attribute data) wraps without switching nametable
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.io.v = (self.io.v & !0x03E0) | (coarse_y << 5);
+    }
+    /// v: ....A.. ...BCDEF <- t: ....A.. ...BCDEF
+    fn copy_x(&mut self) {
+        self.io.v = (self.io.v & !0x041F) | (self.io.t & 0x041F);
+    }
+    /// v: GHIA.BC DEF..... <- t: GHIA.BC DEF.....
+    fn copy_y(&mut self) {
+        self.io.v = (self.io.v & !0x7BE0) | (self.io.t & 0x7BE0);
+    }
+
+    /// Put the fetched tile into the low byte of the shift registers
+    fn load_shifters(&mut self) {
+        let bg = &mut self.bg;
+        bg.shift_pattern_low = (bg.shift_pattern_low & 0xFF00) | bg.pattern_low as u16;
+        bg.shift_pattern_high = (bg.shift_pattern_high & 0xFF00) | bg.pattern_high as u16;
+        bg.shift_attr_low = (bg.shift_attr_low & 0xFF00) | if bg.attribute & 0b01 != 0 { 0xFF } else { 0x00 };
+        bg.shift_attr_high = (bg.shift_attr_high & 0xFF00) | if bg.attribute & 0b10 != 0 { 0xFF } else { 0x00 };
+    }
+    fn shift(&mut self) {
+        let bg = &mut self.bg;
+        bg.shift_pattern_low <<= 1;
+        bg.shift_pattern_high <<= 1;
+        bg.shift_attr_low <<= 1;
+        bg.shift_attr_high <<= 1;
+    }
+    /// Memory fetch for the current dot, each tile takes 8 dots: nametable, attribute, pattern low, pattern high
+    fn fetch_background(&mut self, cart: &Cartridge) {
+        let v = self.io.v;
+        match (self.dot - 1) % 8 {
+            0 => {
+                self.load_shifters();
+                self.bg.nametable = self.read_vram(cart, 0x2000 | (v & 0x0FFF));
+            }
+            2 => {
+                let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                // Each attribute byte covers 4x4 tiles, pick the 2x2 quadrant of the current tile
+                let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                self.bg.attribute = (self.read_vram(cart, addr) >> shift) & 0b11;
+            }
+            4 => self.bg.pattern_low = self.read_vram(cart, self.pattern_addr()),
+            6 => self.bg.pattern_high = self.read_vram(cart, self.pattern_addr() + 8),
+            7 => self.increment_x(),
+            _ => {}
+        }
+    }
+    /// Address of the low pattern plane of the current background tile row
+    fn pattern_addr(&self) -> u16 {
+        let table = if self.io.ctrl.contains(PPUCtrl::BgPatTblAddr) { 0x1000 } else { 0 };
+        table | (self.bg.nametable as u16) << 4 | (self.io.v >> 12) & 0b111
+    }
+    /// Background palette index (0-15) at the current dot, 0 is transparent
+    fn background_pixel(&self) -> u8 {
+        let x = self.dot - 1;
+        if !self.io.mask.contains(PPUMask::BgShow) || (x < 8 && !self.io.mask.contains(PPUMask::BgLeftmostShow)) {
+            return 0
+        }
+        let bit = 0x8000 >> self.io.x;
+        let bg = &self.bg;
+        let pixel = (bg.shift_pattern_low & bit != 0) as u8 | ((bg.shift_pattern_high & bit != 0) as u8) << 1;
+        if pixel == 0 { return 0 }
+        let palette = (bg.shift_attr_low & bit != 0) as u8 | ((bg.shift_attr_high & bit != 0) as u8) << 1;
+        palette << 2 | pixel
+    }
+
+    /// Sprite height in pixels (8 or 16)
+    pub fn sprite_height(&self) -> u16 {
+        if self.io.ctrl.contains(PPUCtrl::SpriteSize) { 16 } else { 8 }
+    }
+    /// Pattern table address of a row (already vertically flipped) of a sprite's tile
+    pub fn sprite_pattern_addr(&self, tile: u8, row: u16) -> u16 {
+        if self.sprite_height() == 16 {
+            // 8x16 sprites pick the pattern table with bit 0 of the tile index
+            let table = (tile as u16 & 1) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + (row >= 8) as u16;
+            table | tile << 4 | (row & 0b111)
+        } else {
+            let table = if self.io.ctrl.contains(PPUCtrl::PatTblAddrType) { 0x1000 } else { 0 };
+            table | (tile as u16) << 4 | row
+        }
+    }
+    /// Find the first 8 sprites in range of the current scanline (they are drawn on the next one), see:
+    /// https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height();
+        let in_range = |y: u8| self.scanline.wrapping_sub(y as u16) < height;
+        self.secondary_oam = [0xFF; 0x20];
+        self.secondary_count = 0;
+        self.secondary_zero = false;
+        let mut n = 0;
+        while n < 64 && self.secondary_count < 8 {
+            let entry = &self.oam[n * 4..n * 4 + 4];
+            if in_range(entry[0]) {
+                self.secondary_oam[self.secondary_count * 4..][..4].copy_from_slice(entry);
+                self.secondary_count += 1;
+                self.secondary_zero |= n == 0;
+            }
+            n += 1;
+        }
+        // With 8 sprites found, the PPU keeps looking for a 9th one, but increments the byte index along with the
+        // sprite index, so it checks tile, attribute and X bytes as if they were Y coordinates
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.io.status.insert(PPUStatus::SpriteOverflow);
+                break
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+    }
+    /// Fetch the patterns of the sprites in secondary OAM for the next scanline
+    fn load_sprites(&mut self, cart: &Cartridge) {
+        let height = self.sprite_height();
+        for i in 0..self.secondary_count {
+            let [y, tile, attr, x] = self.secondary_oam[i * 4..i * 4 + 4].try_into().unwrap();
+            let mut row = self.scanline.wrapping_sub(y as u16);
+            if attr & SPRITE_FLIP_V != 0 { row = height - 1 - row; }
+            let addr = self.sprite_pattern_addr(tile, row);
+            let mut pattern_low = self.read_vram(cart, addr);
+            let mut pattern_high = self.read_vram(cart, addr + 8);
+            if attr & SPRITE_FLIP_H != 0 {
+                pattern_low = pattern_low.reverse_bits();
+                pattern_high = pattern_high.reverse_bits();
+            }
+            self.sprites[i] = Sprite { x, attr, pattern_low, pattern_high };
+        }
+        self.sprite_count = self.secondary_count;
+        self.sprite_zero = self.secondary_zero;
+    }
+    /// First opaque sprite pixel at the current dot: palette index (16-31), behind background, is sprite 0
+    fn sprite_pixel(&self) -> Option<(u8, bool, bool)> {
+        let x = self.dot - 1;
+        if !self.io.mask.contains(PPUMask::SpritesShow) || (x < 8 && !self.io.mask.contains(PPUMask::SpritesLeftmostShow)) {
+            return None
+        }
+        self.sprites[..self.sprite_count].iter().enumerate().find_map(|(i, sprite)| {
+            let offset = x.wrapping_sub(sprite.x as u16);
+            if offset >= 8 { return None }
+            let bit = 0x80 >> offset;
+            let pixel = (sprite.pattern_low & bit != 0) as u8 | ((sprite.pattern_high & bit != 0) as u8) << 1;
+            if pixel == 0 { return None }
+            let index = 0x10 | (sprite.attr & 0b11) << 2 | pixel;
+            Some((index, sprite.attr & SPRITE_BEHIND_BG != 0, i == 0 && self.sprite_zero))
+        })
+    }
+    /// Combine background and sprite pixels into a palette index
+    fn pixel(&mut self) -> u8 {
+        let bg = self.background_pixel();
+        let Some((sprite, behind, zero)) = self.sprite_pixel() else { return bg };
+        if bg == 0 { return sprite }
+        // Opaque sprite 0 over opaque background, except at the rightmost pixel
+        if zero && self.dot != WIDTH as u16 {
+            self.io.status.insert(PPUStatus::Sprite0Hit);
+        }
+        if behind { bg } else { sprite }
+    }
+
+    /// Scanline that starts vertical blanking
+    fn vblank_line(&self) -> u16 { self.region.vblank_line() }
+    /// Last scanline of the frame, prepares the first visible one
+    fn pre_render_line(&self) -> u16 { self.region.scanlines() - 1 }
+
+    /// Run a single PPU dot
+    pub fn step(&mut self, cart: &Cartridge) {
+        let rendering = self.rendering_enabled();
+        let render_line = self.scanline < HEIGHT as u16 || self.scanline == self.pre_render_line();
+
+        if self.scanline == self.pre_render_line() && self.dot == 1 {
+            self.io.status.remove(PPUStatus::VBlank | PPUStatus::Sprite0Hit | PPUStatus::SpriteOverflow);
+        }
+        if self.scanline == self.vblank_line() && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.io.status.insert(PPUStatus::VBlank);
+                self.nmi |= self.io.ctrl.contains(PPUCtrl::VBlankNMI);
+            }
+            self.suppress_vblank = false;
+        }
+
+        if rendering && render_line {
+            if (2..=257).contains(&self.dot) || (321..=337).contains(&self.dot) {
+                self.shift();
+                self.fetch_background(cart);
+            }
+            match self.dot {
+                256 => self.increment_y(),
+                257 => {
+                    self.load_shifters();
+                    self.copy_x();
+                }
+                280..=304 if self.scanline == self.pre_render_line() => self.copy_y(),
+                _ => {}
+            }
+            match self.dot {
+                256 if self.scanline < HEIGHT as u16 => self.evaluate_sprites(),
+                256 => self.secondary_count = 0,
+                // OAMADDR is cleared during sprite fetches
+                257..=320 => {
+                    self.io.oam_addr = 0;
+                    if self.dot == 320 { self.load_sprites(cart); }
+                }
+                _ => {}
+            }
+        }
+
+        if self.scanline < HEIGHT as u16 && (1..=WIDTH as u16).contains(&self.dot) {
+            let index = self.pixel();
+            let color = self.read_vram(cart, 0x3F00 | index as u16) as u16;
+            let emphasis = (self.io.mask.bits() >> 5) as u16;
+            self.framebuffer[self.scanline as usize * WIDTH + self.dot as usize - 1] = emphasis << 6 | color;
+        }
+
+        if self.scanline == 0 && self.dot == 0 {
+            self.frame_phase = self.dot_phase;
+        }
+        self.dot_phase = (self.dot_phase + 1) % 3;
+        self.dot += 1;
+        // The NTSC pre-render line is one dot shorter on odd frames when rendering. `frame_count` already counts the
+        // frame being finished here, so it can't be used for the parity.
+        if self.scanline == self.pre_render_line() && self.dot == DOTS - 1 && rendering && self.odd_frame
+            && self.region.skips_odd_frame_dot() {
+            self.dot += 1;
+        }
+        if self.dot == DOTS {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == HEIGHT as u16 {
+                self.frame_count += 1;
+            } else if self.scanline == self.region.scanlines() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cartridge whose CHR has tile N (1-3) filled with pixel value N
+    fn solid_tiles() -> Cartridge {
+        let mut chr = vec![0u8; 0x2000];
+        for tile in 1..4usize {
+            chr[tile * 16..][..8].fill(if tile & 1 != 0 { 0xFF } else { 0 });
+            chr[tile * 16 + 8..][..8].fill(if tile & 2 != 0 { 0xFF } else { 0 });
+        }
+        Cartridge { chr, mirroring: Mirroring::Vertical, ..Default::default() }
+    }
+
+    fn run_frames(ppu: &mut PPU, cart: &Cartridge, frames: u64) {
+        while ppu.frame_count < frames { ppu.step(cart); }
+    }
+
+    #[test]
+    fn first_tile_of_line() {
+        let mut cart = solid_tiles();
+        let mut ppu = PPU::default();
+        // Nametable 0 is tile 1 except for tile 2 in column 0, nametable 1 (fetched past the right edge) is tile 3
+        for i in 0..0x3C0 {
+            ppu.write_vram(&mut cart, 0x2000 + i, if i % 32 == 0 { 2 } else { 1 });
+            ppu.write_vram(&mut cart, 0x2400 + i, 3);
+        }
+        for (i, color) in [0x0F, 0x11, 0x22, 0x33].into_iter().enumerate() {
+            ppu.write_vram(&mut cart, 0x3F00 + i as u16, color);
+        }
+        ppu.write_register(0x2001, (PPUMask::BgShow | PPUMask::BgLeftmostShow).bits(), &mut cart);
+        run_frames(&mut ppu, &cart, 2);
+
+        for y in [0, 8, 100, HEIGHT - 1] {
+            let line = &ppu.framebuffer[y * WIDTH..][..16];
+            assert_eq!(line[..8], [0x22; 8], "first tile of line {y}");
+            assert_eq!(line[8..], [0x11; 8], "second tile of line {y}");
+        }
+    }
+
+    #[test]
+    fn sprite_pattern_addr() {
+        let mut ppu = PPU::default();
+        ppu.io.ctrl = PPUCtrl::PatTblAddrType;
+        assert_eq!(ppu.sprite_pattern_addr(0x03, 5), 0x1035);
+        // 8x16: odd tiles use the second pattern table, the bottom half is the next tile
+        ppu.io.ctrl = PPUCtrl::SpriteSize;
+        assert_eq!(ppu.sprite_pattern_addr(0x03, 9), 0x1031);
+        assert_eq!(ppu.sprite_pattern_addr(0x04, 7), 0x0047);
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot() {
+        let mut cart = solid_tiles();
+        let mut ppu = PPU::default();
+        ppu.write_register(0x2001, PPUMask::BgShow.bits(), &mut cart);
+        run_frames(&mut ppu, &cart, 1);
+        let lengths: Vec<u32> = (2..6).map(|frame| {
+            let mut dots = 0;
+            while ppu.frame_count < frame {
+                ppu.step(&cart);
+                dots += 1;
+            }
+            dots
+        }).collect();
+        assert_eq!(lengths, [89342, 89341, 89342, 89341]);
+    }
+
+    /// FNV-1a, unlike `DefaultHasher` it is the same on every Rust version
+    fn fnv1a(pixels: &[u16]) -> u64 {
+        pixels.iter().flat_map(|pixel| pixel.to_le_bytes())
+            .fold(0xCBF2_9CE4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01B3))
+    }
+
+    #[test]
+    fn frame_hashes() {
+        let mut cart = solid_tiles();
+        let mut ppu = PPU::default();
+        for i in 0..0x3C0u16 {
+            ppu.write_vram(&mut cart, 0x2000 + i, (i * 7 % 4) as u8);
+            ppu.write_vram(&mut cart, 0x2400 + i, (i % 3) as u8 + 1);
+        }
+        for i in 0..0x40u16 {
+            ppu.write_vram(&mut cart, 0x23C0 + i, (i * 0x1B) as u8);
+        }
+        for i in 0..0x20u16 {
+            ppu.write_vram(&mut cart, 0x3F00 + i, (i * 5 % 0x40) as u8);
+        }
+        // A few sprites, some flipped or behind the background
+        for n in 0..12u8 {
+            ppu.oam[n as usize * 4..][..4].copy_from_slice(&[n * 19, n % 4, n.wrapping_mul(0x41) & 0xE3, n * 21]);
+        }
+        ppu.write_register(0x2005, 37, &mut cart);
+        ppu.write_register(0x2005, 0, &mut cart);
+        ppu.write_register(0x2001, 0x1E, &mut cart);
+
+        let frames: Vec<(u64, u8)> = (1..=4).map(|frame| {
+            run_frames(&mut ppu, &cart, frame);
+            (fnv1a(ppu.framebuffer.as_slice()), ppu.frame_phase)
+        }).collect();
+        // Power on skips the first pre-render line and its fetches, so the first frame differs. The picture is still
+        // after that, while the NTSC color phase alternates with the frame length.
+        assert_eq!(frames, [
+            (0x6856_D19E_3634_A5F9, 0), (0x0646_35F8_9A87_BAE5, 2), (0x0646_35F8_9A87_BAE5, 0), (0x0646_35F8_9A87_BAE5, 2),
+        ]);
+    }
+}
@@ -0,0 +1,172 @@
+//! Debug views of the PPU's memory: pattern tables, nametables, palette RAM and OAM.
+//! Everything is read through `PPU::read_vram`, which has no side effects, so dumping does not change emulation.
+//! Images use the framebuffer's pixel format (color index with emphasis bits) and are colored with a `Palette`.
+
+use std::{fs, fmt::Write as _, path::Path};
+
+use crate::{
+    cartridge::Cartridge,
+    palette::Palette,
+    ppu::{PPU, PPUCtrl, WIDTH, HEIGHT, SPRITE_BEHIND_BG, SPRITE_FLIP_H, SPRITE_FLIP_V},
+    screenshot::{self, ScreenshotError},
+};
+
+/// Size of a pattern table image, 16x16 tiles
+pub const PATTERN_SIZE: usize = 128;
+/// Size of a swatch in the palette image
+const SWATCH_SIZE: usize = 16;
+/// Color of the scroll window outline
+const OUTLINE: [u8; 3] = [255, 0, 255];
+
+/// Color index of a pixel (0-3) from a palette in palette RAM (0-3 background, 4-7 sprites)
+fn palette_color(ppu: &PPU, cart: &Cartridge, palette: u8, pixel: u8) -> u16 {
+    let index = if pixel == 0 { 0 } else { palette << 2 | pixel };
+    ppu.read_vram(cart, 0x3F00 | index as u16) as u16
+}
+
+/// Pixel values (0-3) of one row of a tile
+fn tile_row(ppu: &PPU, cart: &Cartridge, addr: u16) -> [u8; 8] {
+    let low = ppu.read_vram(cart, addr);
+    let high = ppu.read_vram(cart, addr + 8);
+    std::array::from_fn(|x| (low >> (7 - x) & 1) | (high >> (7 - x) & 1) << 1)
+}
+
+/// Draw a tile of a pattern table into an image `width` pixels wide
+fn draw_tile(ppu: &PPU, cart: &Cartridge, image: &mut [u16], width: usize, (x, y): (usize, usize), tile_addr: u16, palette: u8) {
+    for row in 0..8 {
+        for (col, pixel) in tile_row(ppu, cart, tile_addr + row as u16).into_iter().enumerate() {
+            image[(y + row) * width + x + col] = palette_color(ppu, cart, palette, pixel);
+        }
+    }
+}
+
+/// Pattern table 0 ($0000) or 1 ($1000) as 128x128 pixels, colored with a palette from palette RAM
+pub fn pattern_table(ppu: &PPU, cart: &Cartridge, table: u16, palette: u8) -> Vec<u16> {
+    let mut image = vec![0u16; PATTERN_SIZE * PATTERN_SIZE];
+    for tile in 0..256u16 {
+        let pos = ((tile % 16) as usize * 8, (tile / 16) as usize * 8);
+        draw_tile(ppu, cart, &mut image, PATTERN_SIZE, pos, (table * 0x1000) | (tile << 4), palette);
+    }
+    image
+}
+
+/// The four nametables as 512x480 pixels, laid out as they are addressed ($2000 top left, $2C00 bottom right)
+pub fn nametables(ppu: &PPU, cart: &Cartridge) -> Vec<u16> {
+    let width = WIDTH * 2;
+    let mut image = vec![0u16; width * HEIGHT * 2];
+    let pattern_table = if ppu.io.ctrl.contains(PPUCtrl::BgPatTblAddr) { 0x1000 } else { 0 };
+    for table in 0..4u16 {
+        let base = 0x2000 | table << 10;
+        for row in 0..30u16 {
+            for col in 0..32u16 {
+                let tile = ppu.read_vram(cart, base | row << 5 | col) as u16;
+                let attribute = ppu.read_vram(cart, base | 0x3C0 | (row / 4) << 3 | (col / 4));
+                let palette = attribute >> ((row & 2) << 1 | (col & 2)) & 0b11;
+                let pos = ((table & 1) as usize * WIDTH + col as usize * 8, (table >> 1) as usize * HEIGHT + row as usize * 8);
+                draw_tile(ppu, cart, &mut image, width, pos, pattern_table | tile << 4, palette);
+            }
+        }
+    }
+    image
+}
+
+/// Top left corner of the visible area in the nametables image, from the scroll position in t and fine X
+pub fn scroll_origin(ppu: &PPU) -> (usize, usize) {
+    let t = ppu.io.t as usize;
+    let x = (t >> 10 & 1) * WIDTH + (t & 0x1F) * 8 + ppu.io.x as usize;
+    let y = (t >> 11 & 1) * HEIGHT + (t >> 5 & 0x1F) * 8 + (t >> 12 & 0b111);
+    (x, y)
+}
+
+/// The 32 entries of palette RAM, as color indices
+pub fn palette_ram(ppu: &PPU, cart: &Cartridge) -> [u16; 32] {
+    std::array::from_fn(|i| ppu.read_vram(cart, 0x3F00 | i as u16) as u16)
+}
+
+/// A sprite in OAM
+#[derive(Debug, Clone, Copy)]
+pub struct OamEntry {
+    pub y: u8,
+    pub tile: u8,
+    pub attr: u8,
+    pub x: u8,
+}
+impl OamEntry {
+    pub fn palette(&self) -> u8 { 4 + (self.attr & 0b11) }
+    pub fn behind_background(&self) -> bool { self.attr & SPRITE_BEHIND_BG != 0 }
+    pub fn flip_h(&self) -> bool { self.attr & SPRITE_FLIP_H != 0 }
+    pub fn flip_v(&self) -> bool { self.attr & SPRITE_FLIP_V != 0 }
+}
+
+/// The 64 sprites in OAM
+pub fn oam(ppu: &PPU) -> Vec<OamEntry> {
+    ppu.oam.as_chunks::<4>().0.iter().map(|&[y, tile, attr, x]| OamEntry { y, tile, attr, x }).collect()
+}
+
+/// The 64 sprites as an 8x8 grid of 8x8 or 8x16 cells, drawn with their palette and flip bits
+pub fn sprites(ppu: &PPU, cart: &Cartridge) -> (usize, usize, Vec<u16>) {
+    let height = ppu.sprite_height() as usize;
+    let (width, image_height) = (8 * 8, 8 * height);
+    let mut image = vec![0u16; width * image_height];
+    for (i, sprite) in oam(ppu).iter().enumerate() {
+        for row in 0..height {
+            let src_row = if sprite.flip_v() { height - 1 - row } else { row };
+            let mut pixels = tile_row(ppu, cart, ppu.sprite_pattern_addr(sprite.tile, src_row as u16));
+            if sprite.flip_h() { pixels.reverse(); }
+            for (col, pixel) in pixels.into_iter().enumerate() {
+                image[((i / 8) * height + row) * width + (i % 8) * 8 + col] = palette_color(ppu, cart, sprite.palette(), pixel);
+            }
+        }
+    }
+    (width, image_height, image)
+}
+
+/// Text table of the sprites in OAM
+pub fn oam_table(ppu: &PPU) -> String {
+    let mut out = String::from("  #    X    Y  Tile  Pal  Priority  FlipH  FlipV\n");
+    for (i, sprite) in oam(ppu).iter().enumerate() {
+        writeln!(out, "{i:3}  {:3}  {:3}   ${:02X}    {}  {:>8}  {:>5}  {:>5}",
+            sprite.x, sprite.y, sprite.tile, sprite.palette(),
+            if sprite.behind_background() { "back" } else { "front" }, sprite.flip_h(), sprite.flip_v()).unwrap();
+    }
+    out
+}
+
+/// Write all debug views into a directory: `pattern0.png`, `pattern1.png`, `nametables.png`, `palette.png`,
+/// `sprites.png` and `oam.txt`
+pub fn dump(ppu: &PPU, cart: &Cartridge, palette: &Palette, pattern_palette: u8, dir: &Path) -> Result<(), ScreenshotError> {
+    fs::create_dir_all(dir)?;
+    for table in 0..2 {
+        let rgb = screenshot::frame_rgb(&pattern_table(ppu, cart, table, pattern_palette), palette);
+        screenshot::write_image(&dir.join(format!("pattern{table}.png")), PATTERN_SIZE, PATTERN_SIZE, &rgb)?;
+    }
+
+    let (width, height) = (WIDTH * 2, HEIGHT * 2);
+    let mut rgb = screenshot::frame_rgb(&nametables(ppu, cart), palette);
+    // Outline the visible area, which wraps around the edges
+    let (origin_x, origin_y) = scroll_origin(ppu);
+    for i in 0..WIDTH {
+        for dy in [0, HEIGHT - 1] {
+            let (x, y) = ((origin_x + i) % width, (origin_y + dy) % height);
+            rgb[(y * width + x) * 3..][..3].copy_from_slice(&OUTLINE);
+        }
+    }
+    for i in 0..HEIGHT {
+        for dx in [0, WIDTH - 1] {
+            let (x, y) = ((origin_x + dx) % width, (origin_y + i) % height);
+            rgb[(y * width + x) * 3..][..3].copy_from_slice(&OUTLINE);
+        }
+    }
+    screenshot::write_image(&dir.join("nametables.png"), width, height, &rgb)?;
+
+    // 16 entries per row: background palettes on top, sprite palettes below
+    let entries = palette_ram(ppu, cart);
+    let (width, height) = (16 * SWATCH_SIZE, 2 * SWATCH_SIZE);
+    let swatches: Vec<u16> = (0..width * height).map(|i| entries[(i / width / SWATCH_SIZE) * 16 + (i % width) / SWATCH_SIZE]).collect();
+    screenshot::write_image(&dir.join("palette.png"), width, height, &screenshot::frame_rgb(&swatches, palette))?;
+
+    let (width, height, image) = sprites(ppu, cart);
+    screenshot::write_image(&dir.join("sprites.png"), width, height, &screenshot::frame_rgb(&image, palette))?;
+    fs::write(dir.join("oam.txt"), oam_table(ppu))?;
+    Ok(())
+}
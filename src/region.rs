@@ -0,0 +1,94 @@
+//! Timing differences between the NTSC (North America, Japan), PAL (Europe) and Dendy (PAL famiclone) consoles, see:
+//! https://www.nesdev.org/wiki/Cycle_reference_chart
+//! All clocks are divided from one master clock, the ratio of the CPU and PPU dividers sets the dots per CPU cycle.
+
+/// Console timing region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    /// PAL famiclone: PAL frame rate and scanline count with an NTSC-like CPU:PPU ratio, vblank starts late
+    Dendy,
+}
+impl Region {
+    /// Region from the CPU/PPU timing field of an NES 2.0 header (byte 12), None for multi-region games
+    pub fn from_nes2(timing: u8) -> Option<Self> {
+        match timing & 0b11 {
+            0 => Some(Region::Ntsc),
+            1 => Some(Region::Pal),
+            3 => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+    /// Master clock rate in Hz
+    pub fn master_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => 21_477_272.0,
+            Region::Pal | Region::Dendy => 26_601_712.0,
+        }
+    }
+    /// Master clock ticks per CPU cycle
+    pub fn cpu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+    /// Master clock ticks per PPU dot
+    pub fn ppu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+    /// CPU clock rate in Hz
+    pub fn cpu_clock(self) -> f64 {
+        self.master_clock() / self.cpu_divider() as f64
+    }
+    /// Scanlines per frame, including the pre-render line
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+    /// Scanline on which the vblank flag is set and NMI raised. Dendy has 51 post-render lines before it instead of 1.
+    pub fn vblank_line(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+    /// The pre-render line is one dot shorter on odd frames, only done by the NTSC PPU
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+    /// PPUMASK bit 5 emphasizes green and bit 6 red, instead of the other way around
+    pub fn swaps_emphasis(self) -> bool {
+        self != Region::Ntsc
+    }
+    /// CPU cycles after a frame counter reset at which the frame sequencer steps, the 5th step is only in 5-step mode.
+    /// Dendy's APU runs on NTSC timing.
+    pub fn frame_counter_steps(self) -> [u32; 5] {
+        match self {
+            Region::Ntsc | Region::Dendy => [7457, 14913, 22371, 29829, 37281],
+            Region::Pal => [8313, 16627, 24939, 33253, 41565],
+        }
+    }
+    /// DMC output rates in CPU cycles, indexed by the rate of $4010
+    pub fn dmc_rates(self) -> [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54],
+            Region::Pal => [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50],
+        }
+    }
+    /// Noise timer periods in CPU cycles, indexed by the period of $400E
+    pub fn noise_periods(self) -> [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068],
+            Region::Pal => [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778],
+        }
+    }
+}
@@ -3,7 +3,9 @@ use std::{path::{PathBuf, Path}, fs};
 use bytes::Buf;
 use thiserror::Error;
 
-use crate::State;
+use crate::{State, patch, region::Region};
+use crate::cartridge::{Cartridge, Mirroring, mapper_from_id, PRG_BANK_SIZE, PRG_RAM_SIZE, CHR_RAM_SIZE};
+use crate::gamedb::{GameDb, GameEntry, RomHash};
 
 #[derive(Error, Debug)]
 pub enum ROMError {
@@ -11,6 +13,52 @@ pub enum ROMError {
     IOError(#[from] std::io::Error),
     #[error("invalid magic value: {0:x?} .nes file should have magic bytes [4e, 45, 53, 1a] at the beginning.")]
     InvalidMagicValue([u8; 4]),
+    #[error("rom file is truncated: expected at least {expected} bytes, found {found}")]
+    Truncated { expected: usize, found: usize },
+    #[error("unsupported mapper: {0}")]
+    UnsupportedMapper(u16),
+    #[error("raw binary of {size} bytes does not fit in memory when loaded at {load_addr:#06X}")]
+    RawTooLarge { size: usize, load_addr: u16 },
+    #[error("malformed record on line {line}: {reason}")]
+    MalformedRecord { line: usize, reason: &'static str },
+    #[error("Intel HEX checksum mismatch on line {line}: expected {expected:#04x}, found {found:#04x}")]
+    IntelHexChecksum { line: usize, expected: u8, found: u8 },
+    #[error("S-record checksum mismatch on line {line}: expected {expected:#04x}, found {found:#04x}")]
+    SRecordChecksum { line: usize, expected: u8, found: u8 },
+    #[error("record on line {line} is outside of the 64KB address space: {addr:#x}")]
+    AddressOutOfRange { line: usize, addr: u32 },
+    #[error("unknown patch format with magic bytes {0:x?}, expected IPS, UPS or BPS")]
+    UnknownPatchFormat(Vec<u8>),
+    #[error("malformed patch: {0}")]
+    MalformedPatch(&'static str),
+    #[error("patch checksum mismatch for {which}: expected CRC32 {expected:08x}, found {found:08x}")]
+    PatchChecksum { which: &'static str, expected: u32, found: u32 },
+    #[error("patch size mismatch for {which}: expected {expected} bytes, found {found}")]
+    PatchSizeMismatch { which: &'static str, expected: usize, found: usize },
+    #[error("patches can't be applied to {0} images, convert the image to a raw binary first")]
+    PatchUnsupported(&'static str),
+    #[error("malformed NSF file: {0}")]
+    MalformedNSF(&'static str),
+    #[error("track {track} does not exist, the NSF file has {songs} songs")]
+    InvalidTrack { track: u8, songs: u8 },
+    #[error("NSF routine at {0:#06X} did not return")]
+    NSFRoutineFailed(u16),
+    #[error("malformed FDS disk image: {0}")]
+    MalformedDisk(&'static str),
+    #[error("disk side {side} does not exist, the image has {sides} sides")]
+    InvalidDiskSide { side: usize, sides: usize },
+    #[error("FDS BIOS should be 8192 bytes, found {0}")]
+    InvalidBios(usize),
+    #[error("FDS disk images need a BIOS, pass it with --fds-bios")]
+    MissingBios,
+    #[error("disk sides can only be swapped when running an FDS disk image")]
+    NotDiskImage,
+    #[error("malformed UNIF file: {0}")]
+    MalformedUNIF(&'static str),
+    #[error("unsupported UNIF board: {0}")]
+    UnsupportedBoard(String),
+    #[error("UNIF {chunk} chunk checksum mismatch: expected CRC32 {expected:08x}, found {found:08x}")]
+    UNIFChecksum { chunk: String, expected: u32, found: u32 },
 }
 
 bitflags::bitflags! {
@@ -35,43 +83,561 @@ bitflags::bitflags! {
     }
 }
 
-pub fn load_rom(path: &Path, state: &mut State) -> Result<Vec<u8>, ROMError> {
-    let file = fs::read(path)?;
+/// Program image formats that can be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// iNES / NES 2.0 cartridge
+    INES,
+    /// Raw binary without header
+    Raw,
+    /// Intel HEX records
+    IntelHex,
+    /// Motorola S-records
+    SRecord,
+    /// Famicom Disk System disk image
+    FDS,
+    /// UNIF cartridge
+    UNIF,
+}
+impl ImageFormat {
+    /// Guess the format of an image from its file extension, defaulting to iNES
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("hex" | "ihx" | "ihex") => ImageFormat::IntelHex,
+            Some("s19" | "s28" | "s37" | "srec" | "mot") => ImageFormat::SRecord,
+            Some("fds") => ImageFormat::FDS,
+            Some("unf" | "unif") => ImageFormat::UNIF,
+            _ => ImageFormat::INES,
+        }
+    }
+}
+
+/// Information about a loaded iNES ROM
+#[derive(Debug)]
+pub struct RomInfo {
+    /// ROM image after patching
+    pub image: Vec<u8>,
+    /// Hash of PRG + CHR contents
+    pub hash: RomHash,
+    /// Game database entry matching the hash
+    pub db_entry: Option<GameEntry>,
+    /// Header fields that were wrong and got corrected from the database entry
+    pub corrections: Vec<String>,
+}
+
+/// Read an image file and apply the given IPS / UPS / BPS patches to it in order, the file itself is never modified
+pub fn read_patched(path: &Path, patches: &[PathBuf]) -> Result<Vec<u8>, ROMError> {
+    let mut file = fs::read(path)?;
+    for patch in patches {
+        file = patch::apply(file, &fs::read(patch)?)?;
+    }
+    Ok(file)
+}
+
+/// Load an iNES ROM, applying the given IPS / UPS / BPS patches in order to the image before it is parsed.
+/// The ROM file itself is never modified.
+/// If the PRG + CHR contents are found in the game database, its mapper, mirroring and battery values replace the header's.
+pub fn load_rom(path: &Path, patches: &[PathBuf], db: &GameDb, state: &mut State) -> Result<RomInfo, ROMError> {
+    let file = read_patched(path, patches)?;
     let mut nes = file.as_slice();
 
+    if nes.len() < 16 { return Err(ROMError::Truncated { expected: 16, found: nes.len() }) }
     let mut header = &nes[0..16];
     nes.advance(16);
 
     /// Parse NES file format: https://www.nesdev.org/wiki/INES
     if &header[0..4] == b"NES\x1a" {
         header.advance(4);
-        let program_size = header.get_u8() as usize * 16384;
+        let program_size = header.get_u8() as usize * PRG_BANK_SIZE;
         let graphics_size = header.get_u8() as usize * 8192;
-        let flags67 = header.get_u16();
-        let mapper_upper = ((flags67 << 4) as u8) & 0b0000_1111; // Get upper mapper bits
-        let mapper = ((flags67 as u8) & 0b1111_0000) | mapper_upper; // Join upper with lower bits
-        let flags_upper = ((flags67 << 8) as u8) & 0b0000_1111;
-        let flags = NESFlags67::from_bits_retain(((flags67 as u8) << 4) | flags_upper);
+        let flags6 = header.get_u8();
+        let flags7 = header.get_u8();
+        let mut mapper = ((flags7 & 0b1111_0000) | (flags6 >> 4)) as u16; // Join upper with lower bits
+        let flags = NESFlags67::from_bits_retain((flags6 << 4) | (flags7 & 0b0000_1111));
 
         //println!("program_size: {program_size:?}, graphics_size: {graphics_size:?}");
         //println!("mapper: {mapper:#b}, flags: {flags:#b}");
 
-        // Not dealing with Trainer region for now...
+        let mut prg_ram = vec![0u8; PRG_RAM_SIZE];
+        // Trainer is loaded into PRG RAM at $7000
         if flags.contains(NESFlags67::Trainer) {
+            if nes.len() < 512 { return Err(ROMError::Truncated { expected: 16 + 512, found: file.len() }) }
+            prg_ram[0x1000..0x1200].copy_from_slice(&nes[..512]);
             nes.advance(512);
         }
+        let prg_file_offset = file.len() - nes.len();
 
-        // Copy ROM to cartridge ram.
-        // It should be written so that it fits up to the very end of the address space
-        state.mem.cartridge[0x8000-0x4020..0xC000-0x4020].copy_from_slice(&nes[0..0x4000]);
-        state.mem.cartridge[0xC000-0x4020..=0xFFFF-0x4020].copy_from_slice(&nes[0..0x4000]);
-        /* let unused = state.mem.cartridge.len() - nes.len();
-        state.mem.cartridge[unused..].copy_from_slice(&nes[..]);
-        state.mem.bytes_unused = unused as u16; */
-        state.mem.bytes_unused = 0xC000-0x4020;
-        Ok(file)
+        if nes.len() < program_size + graphics_size {
+            return Err(ROMError::Truncated { expected: prg_file_offset + program_size + graphics_size, found: file.len() })
+        }
+        let prg_rom = nes[..program_size].to_vec();
+        let chr = nes[program_size..program_size + graphics_size].to_vec();
+        // Boards without CHR ROM have 8KB of CHR RAM
+        let chr_ram = chr.is_empty();
+
+        let mut mirroring = if flags.contains(NESFlags67::FourScreen) {
+            Mirroring::FourScreen
+        } else if flags.contains(NESFlags67::Mirroring) {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let mut battery = flags.contains(NESFlags67::BatteryRam);
+        // Only NES 2.0 headers have a reliable timing field
+        let nes2 = flags & (NESFlags67::NES2Format | NESFlags67::NES1Format) == NESFlags67::NES2Format;
+        let region = if nes2 { Region::from_nes2(file[12]) } else { None };
+
+        // Fix up bad headers using the game database
+        let hash = RomHash::new(&prg_rom, &chr);
+        let db_entry = db.find(&hash).cloned();
+        let mut corrections = Vec::new();
+        if let Some(entry) = &db_entry {
+            if entry.mapper != mapper {
+                corrections.push(format!("mapper {mapper} -> {}", entry.mapper));
+                mapper = entry.mapper;
+            }
+            if let Some(db_mirroring) = entry.mirroring.filter(|m| *m != mirroring) {
+                corrections.push(format!("mirroring {mirroring:?} -> {db_mirroring:?}"));
+                mirroring = db_mirroring;
+            }
+            if entry.battery != battery {
+                corrections.push(format!("battery {battery} -> {}", entry.battery));
+                battery = entry.battery;
+            }
+        }
+
+        let mapper_impl = mapper_from_id(mapper, program_size).ok_or(ROMError::UnsupportedMapper(mapper))?;
+
+        state.mem.cartridge = Cartridge {
+            mapper_id: mapper,
+            prg_rom,
+            prg_ram,
+            chr: if chr_ram { vec![0u8; CHR_RAM_SIZE] } else { chr },
+            chr_ram,
+            mirroring,
+            battery,
+            region,
+            prg_file_offsets: vec![(0, prg_file_offset)],
+            mapper: mapper_impl,
+        };
+        Ok(RomInfo { image: file, hash, db_entry, corrections })
     } else {
         // println!("{:x?} != {:x?}", &nes[0..4], b"NES\x1a");
-        Err(ROMError::InvalidMagicValue((&nes[0..4]).try_into().unwrap()))
+        Err(ROMError::InvalidMagicValue((&header[0..4]).try_into().unwrap()))
+    }
+}
+
+/// Map a UNIF board name to the iNES mapper implementing it. See: https://www.nesdev.org/wiki/UNIF#Board_names
+fn unif_board_mapper(board: &str) -> Option<u16> {
+    // Board names are prefixed by the manufacturer
+    let name = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-", "IREM-", "KONAMI-", "TAITO-"].iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    Some(match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" | "SROM" => 0,
+        "UNROM" | "UOROM" | "UN1ROM" | "74*161/161/32" => 2,
+        "CNROM" => 3,
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM" | "SLROM"
+            | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => 1,
+        "ANROM" | "AN1ROM" | "AMROM" | "AOROM" => 7,
+        _ => return None,
+    })
+}
+
+/// Load a UNIF cartridge, see: https://www.nesdev.org/wiki/UNIF
+/// PRG and CHR are stored in numbered chunks (PRG0-PRGF, CHR0-CHRF) which are joined in order.
+pub fn load_unif(path: &Path, patches: &[PathBuf], state: &mut State) -> Result<RomInfo, ROMError> {
+    let file = read_patched(path, patches)?;
+    let (cartridge, hash) = parse_unif(&file)?;
+    state.mem.cartridge = cartridge;
+    Ok(RomInfo { image: file, hash, db_entry: None, corrections: Vec::new() })
+}
+
+/// Cartridge described by a UNIF file, and the hash of its PRG and CHR ROM
+fn parse_unif(file: &[u8]) -> Result<(Cartridge, RomHash), ROMError> {
+    if file.len() < 32 { return Err(ROMError::Truncated { expected: 32, found: file.len() }) }
+    if &file[0..4] != b"UNIF" { return Err(ROMError::MalformedUNIF("missing UNIF magic")) }
+
+    let mut board = None;
+    let mut mirroring = Mirroring::Horizontal;
+    let mut battery = false;
+    // Chunk data and file offset, by chunk number
+    let mut prg: [Option<(&[u8], usize)>; 16] = Default::default();
+    let mut chr: [Option<&[u8]>; 16] = Default::default();
+    let mut prg_crc = [None; 16];
+    let mut chr_crc = [None; 16];
+
+    let mut chunks = &file[32..];
+    while chunks.remaining() >= 8 {
+        let id: [u8; 4] = chunks[..4].try_into().unwrap();
+        chunks.advance(4);
+        let len = chunks.get_u32_le() as usize;
+        if chunks.remaining() < len { return Err(ROMError::MalformedUNIF("chunk is truncated")) }
+        let data = &chunks[..len];
+        let offset = file.len() - chunks.len();
+        chunks.advance(len);
+
+        let index = (id[3] as char).to_digit(16).map(|i| i as usize);
+        match (&id[..3], index) {
+            (b"PRG", Some(i)) => prg[i] = Some((data, offset)),
+            (b"CHR", Some(i)) => chr[i] = Some(data),
+            (b"PCK", Some(i)) if data.len() == 4 => prg_crc[i] = Some(u32::from_le_bytes(data.try_into().unwrap())),
+            (b"CCK", Some(i)) if data.len() == 4 => chr_crc[i] = Some(u32::from_le_bytes(data.try_into().unwrap())),
+            _ => match &id {
+                b"MAPR" => {
+                    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+                    board = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+                }
+                b"MIRR" => mirroring = match data.first() {
+                    Some(1) => Mirroring::Vertical,
+                    Some(2) => Mirroring::SingleScreenA,
+                    Some(3) => Mirroring::SingleScreenB,
+                    Some(4) => Mirroring::FourScreen,
+                    // 0: Horizontal, 5: controlled by mapper
+                    _ => Mirroring::Horizontal,
+                },
+                b"BATR" => battery = data.first().is_some_and(|b| *b != 0),
+                _ => {}
+            }
+        }
+    }
+
+    // Verify chunk checksums
+    let check = |name: &str, data: &[u8], expected: Option<u32>| match expected {
+        Some(expected) if crc32fast::hash(data) != expected => {
+            Err(ROMError::UNIFChecksum { chunk: name.to_owned(), expected, found: crc32fast::hash(data) })
+        }
+        _ => Ok(()),
+    };
+    for i in 0..16 {
+        if let Some((data, _)) = prg[i] { check(&format!("PRG{i:X}"), data, prg_crc[i])?; }
+        if let Some(data) = chr[i] { check(&format!("CHR{i:X}"), data, chr_crc[i])?; }
+    }
+
+    let board = board.ok_or(ROMError::MalformedUNIF("missing MAPR chunk"))?;
+    let mapper = unif_board_mapper(&board).ok_or_else(|| ROMError::UnsupportedBoard(board.clone()))?;
+    let mut prg_rom = Vec::new();
+    let mut prg_file_offsets = Vec::new();
+    for (data, offset) in prg.iter().flatten() {
+        prg_file_offsets.push((prg_rom.len(), *offset));
+        prg_rom.extend_from_slice(data);
     }
-}
\ No newline at end of file
+    if prg_rom.is_empty() { return Err(ROMError::MalformedUNIF("no PRG chunks")) }
+    let chr: Vec<u8> = chr.iter().flatten().flat_map(|data| data.iter().copied()).collect();
+    let chr_ram = chr.is_empty();
+
+    let mapper_impl = mapper_from_id(mapper, prg_rom.len()).ok_or(ROMError::UnsupportedBoard(board))?;
+    let hash = RomHash::new(&prg_rom, &chr);
+    let cartridge = Cartridge {
+        mapper_id: mapper,
+        prg_rom,
+        prg_ram: vec![0u8; PRG_RAM_SIZE],
+        chr: if chr_ram { vec![0u8; CHR_RAM_SIZE] } else { chr },
+        chr_ram,
+        mirroring,
+        battery,
+        region: None,
+        prg_file_offsets,
+        mapper: mapper_impl,
+    };
+    Ok((cartridge, hash))
+}
+
+/// Load a raw 6502 binary (no header) at `load_addr` into a flat 64KB RAM bus that replaces the NES memory map.
+/// Patches are applied to the binary before it is loaded.
+pub fn load_raw(path: &Path, patches: &[PathBuf], load_addr: u16, state: &mut State) -> Result<Vec<u8>, ROMError> {
+    let file = read_patched(path, patches)?;
+    let start = load_addr as usize;
+    if start + file.len() > 0x10000 {
+        return Err(ROMError::RawTooLarge { size: file.len(), load_addr })
+    }
+    let mut ram = Box::new([0u8; 0x10000]);
+    ram[start..start + file.len()].copy_from_slice(&file);
+    state.mem.flat = Some(ram);
+    Ok(file)
+}
+
+/// Decode a string of hex digit pairs into bytes
+fn decode_hex(line: usize, digits: &str) -> Result<Vec<u8>, ROMError> {
+    if digits.len() % 2 != 0 { return Err(ROMError::MalformedRecord { line, reason: "odd number of hex digits" }) }
+    let invalid = ROMError::MalformedRecord { line, reason: "invalid hex digit" };
+    let nibble = |digit: u8| (digit as char).to_digit(16).map(|n| n as u8);
+    digits.as_bytes().as_chunks::<2>().0.iter()
+        .map(|&[high, low]| Some(nibble(high)? << 4 | nibble(low)?))
+        .collect::<Option<_>>()
+        .ok_or(invalid)
+}
+
+/// Records are ASCII text, anything else would also break slicing the line by byte index
+fn check_ascii(line: usize, text: &str) -> Result<(), ROMError> {
+    if text.is_ascii() { Ok(()) } else { Err(ROMError::MalformedRecord { line, reason: "non-ASCII character" }) }
+}
+
+/// Copy a record's data into flat memory, checking it fits in the address space
+fn store_segment(ram: &mut [u8; 0x10000], line: usize, addr: u32, data: &[u8]) -> Result<(), ROMError> {
+    let end = addr as usize + data.len();
+    if end > 0x10000 { return Err(ROMError::AddressOutOfRange { line, addr: end as u32 - 1 }) }
+    ram[addr as usize..end].copy_from_slice(data);
+    Ok(())
+}
+
+/// Load an Intel HEX image into a flat 64KB RAM bus. See: https://en.wikipedia.org/wiki/Intel_HEX
+/// Returns the start address if the image contains one. Patches can't be applied to the record text and are rejected.
+pub fn load_intel_hex(path: &Path, patches: &[PathBuf], state: &mut State) -> Result<Option<u16>, ROMError> {
+    if !patches.is_empty() { return Err(ROMError::PatchUnsupported("Intel HEX")) }
+    let (ram, entry) = parse_intel_hex(&fs::read_to_string(path)?)?;
+    state.mem.flat = Some(ram);
+    Ok(entry)
+}
+
+/// Memory contents of an Intel HEX image, and its start address if it has one
+fn parse_intel_hex(file: &str) -> Result<(Box<[u8; 0x10000]>, Option<u16>), ROMError> {
+    let mut ram = Box::new([0u8; 0x10000]);
+    let mut entry = None;
+    // Upper address bits set by extended segment / linear address records
+    let mut base = 0u32;
+
+    for (i, text) in file.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if text.is_empty() { continue }
+        check_ascii(i, text)?;
+        let digits = text.strip_prefix(':').ok_or(ROMError::MalformedRecord { line: i, reason: "record does not start with ':'" })?;
+        let bytes = decode_hex(i, digits)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(ROMError::MalformedRecord { line: i, reason: "record length does not match byte count" })
+        }
+        // Sum of all bytes including the checksum is zero
+        let (record, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)).wrapping_neg();
+        if expected != checksum[0] {
+            return Err(ROMError::IntelHexChecksum { line: i, expected, found: checksum[0] })
+        }
+        let mut record = &record[1..];
+        let offset = record.get_u16() as u32;
+        let kind = record.get_u8();
+        let data = record;
+        match kind {
+            // Data
+            0x00 => store_segment(&mut ram, i, base + offset, data)?,
+            // End of file
+            0x01 => break,
+            // Extended segment address
+            0x02 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            // Extended linear address
+            0x04 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // Start segment address (CS:IP), only IP is meaningful for the 6502
+            0x03 if data.len() == 4 => entry = Some(u16::from_be_bytes([data[2], data[3]])),
+            // Start linear address
+            0x05 if data.len() == 4 => {
+                let addr = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                if addr > 0xFFFF { return Err(ROMError::AddressOutOfRange { line: i, addr }) }
+                entry = Some(addr as u16);
+            }
+            0x02..=0x05 => return Err(ROMError::MalformedRecord { line: i, reason: "wrong data length for address record" }),
+            _ => return Err(ROMError::MalformedRecord { line: i, reason: "unknown record type" }),
+        }
+    }
+    Ok((ram, entry))
+}
+
+/// Load a Motorola S-record image into a flat 64KB RAM bus. See: https://en.wikipedia.org/wiki/SREC_(file_format)
+/// Returns the start address if the image contains one. Patches can't be applied to the record text and are rejected.
+pub fn load_srec(path: &Path, patches: &[PathBuf], state: &mut State) -> Result<Option<u16>, ROMError> {
+    if !patches.is_empty() { return Err(ROMError::PatchUnsupported("S-record")) }
+    let (ram, entry) = parse_srec(&fs::read_to_string(path)?)?;
+    state.mem.flat = Some(ram);
+    Ok(entry)
+}
+
+/// Memory contents of an S-record image, and its start address if it has one
+fn parse_srec(file: &str) -> Result<(Box<[u8; 0x10000]>, Option<u16>), ROMError> {
+    let mut ram = Box::new([0u8; 0x10000]);
+    let mut entry = None;
+
+    for (i, text) in file.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if text.is_empty() { continue }
+        check_ascii(i, text)?;
+        let digits = text.strip_prefix('S').ok_or(ROMError::MalformedRecord { line: i, reason: "record does not start with 'S'" })?;
+        let kind = digits.chars().next().ok_or(ROMError::MalformedRecord { line: i, reason: "missing record type" })?;
+        let bytes = decode_hex(i, &digits[1..])?;
+        if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(ROMError::MalformedRecord { line: i, reason: "record length does not match byte count" })
+        }
+        // Checksum is the ones' complement of the sum of count, address and data bytes
+        let (record, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = !record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        if expected != checksum[0] {
+            return Err(ROMError::SRecordChecksum { line: i, expected, found: checksum[0] })
+        }
+        let addr_len = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(ROMError::MalformedRecord { line: i, reason: "unknown record type" }),
+        };
+        let record = &record[1..];
+        if record.len() < addr_len { return Err(ROMError::MalformedRecord { line: i, reason: "record too short for address" }) }
+        let (addr, data) = record.split_at(addr_len);
+        let addr = addr.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        match kind {
+            // Data
+            '1'..='3' => store_segment(&mut ram, i, addr, data)?,
+            // Start address
+            '7'..='9' => {
+                if addr > 0xFFFF { return Err(ROMError::AddressOutOfRange { line: i, addr }) }
+                entry = Some(addr as u16);
+            }
+            // Header and record counts
+            _ => {}
+        }
+    }
+    Ok((ram, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// UNIF file: 32 byte header, then chunks of id, length and data
+    fn unif(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut file = b"UNIF".to_vec();
+        file.extend(7u32.to_le_bytes());
+        file.resize(32, 0);
+        for (id, data) in chunks {
+            file.extend(*id);
+            file.extend((data.len() as u32).to_le_bytes());
+            file.extend(*data);
+        }
+        file
+    }
+
+    #[test]
+    fn unif_chunks() {
+        let (prg0, prg1, chr) = (vec![0xA0; 0x4000], vec![0xA1; 0x4000], vec![0xC0; 0x2000]);
+        let file = unif(&[
+            (b"MAPR", b"NES-CNROM\0"),
+            (b"READ", b"ignored"),
+            (b"PRG1", &prg1),
+            (b"PRG0", &prg0),
+            (b"PCK0", &crc32fast::hash(&prg0).to_le_bytes()),
+            (b"CHR0", &chr),
+            (b"MIRR", &[1]),
+            (b"BATR", &[1]),
+        ]);
+        let (cart, _) = parse_unif(&file).unwrap();
+        assert_eq!(cart.mapper_id, 3);
+        // PRG chunks are joined by number, not by their order in the file
+        assert_eq!(cart.prg_rom, [prg0, prg1].concat());
+        // Data of PRG1 follows the header, MAPR, READ and its own chunk header, PRG0 comes right after it
+        let prg1_offset = 32 + (8 + 10) + (8 + 7) + 8;
+        assert_eq!(cart.prg_file_offsets, [(0, prg1_offset + 0x4000 + 8), (0x4000, prg1_offset)]);
+        assert_eq!(cart.chr, chr);
+        assert!(!cart.chr_ram);
+        assert_eq!(cart.mirroring, Mirroring::Vertical);
+        assert!(cart.battery);
+
+        // No CHR chunks means CHR RAM
+        let (cart, _) = parse_unif(&unif(&[(b"MAPR", b"UNROM"), (b"PRG0", &[0; 0x8000])])).unwrap();
+        assert_eq!((cart.mapper_id, cart.chr_ram, cart.chr.len()), (2, true, CHR_RAM_SIZE));
+        assert_eq!(cart.mirroring, Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn unif_errors() {
+        let error = |file: &[u8]| parse_unif(file).err().unwrap();
+        let prg: &[u8] = &[0; 0x4000];
+        assert!(matches!(error(b"UNIF"), ROMError::Truncated { expected: 32, found: 4 }));
+        let mut bad = unif(&[(b"MAPR", b"NROM"), (b"PRG0", prg)]);
+        bad[3] = b'G';
+        assert!(matches!(error(&bad), ROMError::MalformedUNIF("missing UNIF magic")));
+        let mut truncated = unif(&[(b"MAPR", b"NROM"), (b"PRG0", prg)]);
+        truncated.pop();
+        assert!(matches!(error(&truncated), ROMError::MalformedUNIF("chunk is truncated")));
+        let file = unif(&[(b"MAPR", b"NROM"), (b"PRG0", prg), (b"PCK0", &[1, 2, 3, 4])]);
+        assert!(matches!(error(&file), ROMError::UNIFChecksum { chunk, expected: 0x0403_0201, .. } if chunk == "PRG0"));
+        assert!(matches!(error(&unif(&[(b"PRG0", prg)])), ROMError::MalformedUNIF("missing MAPR chunk")));
+        assert!(matches!(error(&unif(&[(b"MAPR", b"NES-TLROM"), (b"PRG0", prg)])), ROMError::UnsupportedBoard(board) if board == "NES-TLROM"));
+        assert!(matches!(error(&unif(&[(b"MAPR", b"NROM"), (b"CHR0", prg)])), ROMError::MalformedUNIF("no PRG chunks")));
+    }
+
+    #[test]
+    fn raw_applies_patches() {
+        let dir = std::env::temp_dir().join(format!("em6502-raw-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (bin, ips) = (dir.join("test.bin"), dir.join("test.ips"));
+        fs::write(&bin, [0xA2, 0x00, 0x4C, 0x02, 0x04]).unwrap();
+        fs::write(&ips, [b"PATCH".as_slice(), &[0, 0, 1, 0, 1, 0x05], b"EOF"].concat()).unwrap();
+        let mut state = State::new();
+        load_raw(&bin, &[ips], 0x0400, &mut state).unwrap();
+        assert_eq!(state.mem.flat.as_ref().unwrap()[0x0400..0x0405], [0xA2, 0x05, 0x4C, 0x02, 0x04]);
+        // The file on disk is left as it was
+        assert_eq!(fs::read(&bin).unwrap()[1], 0x00);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn intel_hex_records() {
+        let file = ":0300300002337A1E\n\n:020000020100FB\n:01001000559A\n:040000050000C00037\n:00000001FF\n:0100000055AA\n";
+        let (ram, entry) = parse_intel_hex(file).unwrap();
+        assert_eq!(ram[0x30..0x33], [0x02, 0x33, 0x7A]);
+        // Extended segment address $0100 moves the record at $0010 up to $1010
+        assert_eq!(ram[0x1010], 0x55);
+        assert_eq!(ram[0x0010], 0);
+        assert_eq!(entry, Some(0xC000));
+        // Nothing after the end of file record is loaded
+        assert_eq!(ram[0], 0);
+        // Start segment address, only IP is used
+        assert_eq!(parse_intel_hex(":0400000300001234B3").unwrap().1, Some(0x1234));
+    }
+
+    #[test]
+    fn intel_hex_errors() {
+        let error = |file| parse_intel_hex(file).unwrap_err();
+        assert!(matches!(error(":0300300002337A1F"), ROMError::IntelHexChecksum { line: 1, expected: 0x1E, found: 0x1F }));
+        assert!(matches!(error("\n0300300002337A1E"), ROMError::MalformedRecord { line: 2, .. }));
+        assert!(matches!(error(":0300300002337A1"), ROMError::MalformedRecord { reason: "odd number of hex digits", .. }));
+        assert!(matches!(error(":03003000G2337A1E"), ROMError::MalformedRecord { reason: "invalid hex digit", .. }));
+        assert!(matches!(error(":0400300002337A1E"), ROMError::MalformedRecord { reason: "record length does not match byte count", .. }));
+        assert!(matches!(error(":00000006FA"), ROMError::MalformedRecord { reason: "unknown record type", .. }));
+        assert!(matches!(error(":0é00300002337A1E"), ROMError::MalformedRecord { reason: "non-ASCII character", .. }));
+        // Extended linear address $0001 puts the data past 64KB
+        assert!(matches!(error(":020000040001F9\n:01001000559A"), ROMError::AddressOutOfRange { line: 2, addr: 0x10010 }));
+    }
+
+    #[test]
+    fn srec_records() {
+        let file = "S0060000686472BB\nS1137AF00A0A0D0000000000000000000000000061\nS20600C000A9018F\nS3060000C100EA4E\nS5030003F9\nS903C0003C\n";
+        let (ram, entry) = parse_srec(file).unwrap();
+        assert_eq!(ram[0x7AF0..0x7AF3], [0x0A, 0x0A, 0x0D]);
+        // 24-bit and 32-bit addresses
+        assert_eq!(ram[0xC000..0xC002], [0xA9, 0x01]);
+        assert_eq!(ram[0xC100], 0xEA);
+        assert_eq!(entry, Some(0xC000));
+        // 24-bit start address
+        assert_eq!(parse_srec("S80400C0003B").unwrap().1, Some(0xC000));
+    }
+
+    #[test]
+    fn srec_errors() {
+        let error = |file| parse_srec(file).unwrap_err();
+        assert!(matches!(error("S20600C000A90190"), ROMError::SRecordChecksum { line: 1, expected: 0x8F, found: 0x90 }));
+        assert!(matches!(error(":20600C000A9018F"), ROMError::MalformedRecord { reason: "record does not start with 'S'", .. }));
+        assert!(matches!(error("S"), ROMError::MalformedRecord { reason: "missing record type", .. }));
+        assert!(matches!(error("S4030000FC"), ROMError::MalformedRecord { reason: "unknown record type", .. }));
+        assert!(matches!(error("S30200FD"), ROMError::MalformedRecord { reason: "record too short for address", .. }));
+        assert!(matches!(error("Sé0600C000A9018F"), ROMError::MalformedRecord { reason: "non-ASCII character", .. }));
+        assert!(matches!(error("S20501000001F8"), ROMError::AddressOutOfRange { line: 1, addr: 0x10000 }));
+    }
+
+    #[test]
+    fn records_reject_patches() {
+        let mut state = State::new();
+        let patches = [PathBuf::from("fix.ips")];
+        // Rejected before either file is read
+        let hex = load_intel_hex(Path::new("missing.hex"), &patches, &mut state).unwrap_err();
+        assert!(matches!(hex, ROMError::PatchUnsupported("Intel HEX")));
+        let srec = load_srec(Path::new("missing.s19"), &patches, &mut state).unwrap_err();
+        assert!(matches!(srec, ROMError::PatchUnsupported("S-record")));
+        assert!(state.mem.flat.is_none());
+    }
+}
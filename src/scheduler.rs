@@ -0,0 +1,104 @@
+//! Master clock and timed events. Every component is clocked by dividing the master clock (21.477 MHz on NTSC,
+//! 26.601 MHz on PAL / Dendy), see: https://www.nesdev.org/wiki/Cycle_reference_chart
+//! The CPU drives emulation: each CPU cycle advances the master clock, the other components then catch up to it by
+//! running however many of their own cycles fit, and events that have come due are dispatched.
+//!
+//! Events are for things that happen a fixed time after a CPU access, like DMA starts and delayed register writes.
+//! IRQs are not events: the APU, DMC and mappers raise and acknowledge their IRQ flags themselves while they are
+//! clocked, so each `IrqSource` level is copied from its device after every CPU cycle.
+
+use bitflags::bitflags;
+
+use crate::region::Region;
+
+/// Time in master clock ticks
+pub type Ticks = u64;
+
+bitflags! {
+    /// Devices that can assert the CPU IRQ line, each level is synced from its device every CPU cycle
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct IrqSource: u8 {
+        /// Cartridge mapper (scanline counters, FDS timer and disk transfers)
+        const Mapper       = 0b001;
+        /// APU frame counter
+        const FrameCounter = 0b010;
+        /// APU delta modulation channel
+        const Dmc          = 0b100;
+    }
+}
+
+/// Something that happens at a point in time, a fixed number of CPU cycles after the access that scheduled it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Halt the CPU and copy a page of CPU memory into OAM
+    OamDma(u8),
+    /// A write to $4017 takes effect, resetting the APU frame sequencer
+    FrameCounterWrite(u8),
+}
+
+/// Master clock and the queue of pending events
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    /// Master clock ticks since power on, the CPU has run up to here
+    now: Ticks,
+    /// Master clock ticks per CPU cycle
+    cpu_divider: Ticks,
+    /// Pending events sorted latest first, so the next one is popped from the end
+    events: Vec<(Ticks, Event)>,
+}
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self { now: 0, cpu_divider: Region::default().cpu_divider() as Ticks, events: Vec::new() }
+    }
+}
+impl Scheduler {
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu_divider = region.cpu_divider() as Ticks;
+    }
+    /// Current master clock time
+    pub fn now(&self) -> Ticks { self.now }
+    /// Advance the master clock by one CPU cycle, returns the new time
+    pub fn advance_cpu(&mut self) -> Ticks {
+        self.now += self.cpu_divider;
+        self.now
+    }
+    /// Time at the end of the CPU cycle `cycles` from now, 1 is the end of the current cycle
+    pub fn after_cycles(&self, cycles: u32) -> Ticks {
+        self.now + cycles as Ticks * self.cpu_divider
+    }
+    /// Queue an event, events due at the same time are dispatched in the order they were scheduled
+    pub fn schedule(&mut self, at: Ticks, event: Event) {
+        let i = self.events.partition_point(|&(time, _)| time > at);
+        self.events.insert(i, (at, event));
+    }
+    /// Take the next event that is due by now
+    pub fn pop_due(&mut self) -> Option<Event> {
+        match self.events.last() {
+            Some(&(time, _)) if time <= self.now => self.events.pop().map(|(_, event)| event),
+            _ => None,
+        }
+    }
+}
+
+/// Position of a component on the master clock
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    /// Master clock time the component has run up to
+    time: Ticks,
+    /// Master clock ticks per cycle of the component
+    divider: Ticks,
+}
+impl Clock {
+    pub fn new(divider: u32) -> Self {
+        Self { time: 0, divider: divider as Ticks }
+    }
+    pub fn set_divider(&mut self, divider: u32) {
+        self.divider = divider as Ticks;
+    }
+    /// Claim the next cycle of the component if it fits before `now`, call until false to catch up
+    pub fn tick(&mut self, now: Ticks) -> bool {
+        let fits = self.time + self.divider <= now;
+        if fits { self.time += self.divider; }
+        fits
+    }
+}
@@ -0,0 +1,72 @@
+//! Writing rendered frames to PNG or binary PPM (P6) images.
+
+use std::{fs::File, io::{self, BufWriter, Write}, path::Path};
+
+use thiserror::Error;
+
+use crate::{palette::Palette, ppu::{WIDTH, HEIGHT}, ntsc};
+
+#[derive(Error, Debug)]
+pub enum ScreenshotError {
+    #[error("unable to write image")]
+    IOError(#[from] io::Error),
+    #[error("unable to encode PNG: {0}")]
+    PngError(#[from] png::EncodingError),
+}
+
+/// Convert a PPU framebuffer to 8-bit RGB
+pub fn frame_rgb(framebuffer: &[u16], palette: &Palette) -> Vec<u8> {
+    framebuffer.iter().flat_map(|pixel| palette.rgb(*pixel)).collect()
+}
+
+/// Write 8-bit RGB pixels as an image, PPM if the path ends in `.ppm` and PNG otherwise
+pub fn write_image(path: &Path, width: usize, height: usize, rgb: &[u8]) -> Result<(), ScreenshotError> {
+    let mut out = BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ppm")) {
+        write!(out, "P6\n{width} {height}\n255\n")?;
+        out.write_all(rgb)?;
+        out.flush()?;
+    } else {
+        let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(rgb)?;
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+/// Image format of the frames saved with `--dump-frames`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum FrameFormat {
+    #[default]
+    Png,
+    Ppm,
+}
+impl FrameFormat {
+    /// File extension, which is what `write_image` picks the format by
+    pub fn extension(self) -> &'static str {
+        match self {
+            FrameFormat::Png => "png",
+            FrameFormat::Ppm => "ppm",
+        }
+    }
+}
+
+/// How PPU output is turned into RGB
+#[derive(Debug, Clone)]
+pub enum Video {
+    /// Look up each pixel in a palette
+    Palette(Palette),
+    /// Simulate the NTSC composite signal, see `ntsc::filter`
+    Ntsc,
+}
+
+/// Write a PPU framebuffer as an image, `frame_phase` is the PPU's `frame_phase` for the NTSC filter
+pub fn write_frame(path: &Path, framebuffer: &[u16], frame_phase: u8, video: &Video) -> Result<(), ScreenshotError> {
+    match video {
+        Video::Palette(palette) => write_image(path, WIDTH, HEIGHT, &frame_rgb(framebuffer, palette)),
+        Video::Ntsc => write_image(path, ntsc::NTSC_WIDTH, HEIGHT, &ntsc::filter(framebuffer, frame_phase)),
+    }
+}
@@ -0,0 +1,253 @@
+//! Symbol tables loaded from assembler / debugger label files, used to annotate the trace log.
+//! Supported formats:
+//! - ca65/ld65 debug info (`.dbg`), including source line information: https://cc65.github.io/doc/ld65.html#s5
+//! - FCEUX name lists (`.nl`), where `game.nes.N.nl` holds labels for PRG bank N: https://fceux.com/web/help/NLFilesFormat.html
+//! - VICE label files (`al C:c5f5 .label`)
+
+use std::{collections::{BTreeMap, HashMap}, fs, path::Path};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SymbolError {
+    #[error("failed to read symbol file: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("malformed symbol file on line {line}: {reason}")]
+    Malformed { line: usize, reason: &'static str },
+}
+
+/// Source line that a range of bytes was assembled from
+#[derive(Debug, Clone)]
+struct LineSpan {
+    /// CPU address of the first byte
+    addr: u16,
+    /// Offset of the first byte in the ROM file, if known
+    file_offset: Option<usize>,
+    size: u16,
+    /// Index into `SymbolTable::files`
+    file: usize,
+    line: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    /// Labels valid regardless of bank
+    labels: HashMap<u16, String>,
+    /// Labels only valid for a specific PRG bank
+    banked: HashMap<(usize, u16), String>,
+    files: Vec<String>,
+    lines: Vec<LineSpan>,
+    /// Indices into `lines` by CPU address and by file offset of the first byte
+    lines_by_addr: BTreeMap<u16, Vec<usize>>,
+    lines_by_offset: BTreeMap<usize, Vec<usize>>,
+    /// Size of the largest span, spans starting further back than this can't contain an address
+    max_line_size: u16,
+}
+impl SymbolTable {
+    /// Load symbols from a file, picking the parser by extension (`.dbg`, `.nl`, anything else is a VICE label file)
+    pub fn load(&mut self, path: &Path) -> Result<(), SymbolError> {
+        let text = fs::read_to_string(path)?;
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if name.ends_with(".dbg") {
+            self.load_ld65_dbg(&text)
+        } else if let Some(stem) = name.strip_suffix(".nl") {
+            // `game.nes.3.nl` is bank 3, `game.nes.ram.nl` and others apply to every bank
+            let bank = stem.rsplit('.').next().and_then(|b| b.parse().ok());
+            self.load_fceux_nl(&text, bank)
+        } else {
+            self.load_vice(&text)
+        }
+    }
+    fn insert(&mut self, bank: Option<usize>, addr: u16, name: &str) {
+        match bank {
+            Some(bank) => self.banked.entry((bank, addr)).or_insert_with(|| name.to_owned()),
+            None => self.labels.entry(addr).or_insert_with(|| name.to_owned()),
+        };
+    }
+    /// Look up the label for an address, preferring labels of the given PRG bank
+    pub fn label(&self, addr: u16, bank: Option<usize>) -> Option<&str> {
+        bank.and_then(|bank| self.banked.get(&(bank, addr)))
+            .or_else(|| self.labels.get(&addr))
+            .map(String::as_str)
+    }
+    /// Look up the source file and line that the byte at an address was assembled from.
+    /// The ROM file offset is used when available so that banked code resolves to the right source.
+    pub fn source_line(&self, addr: u16, file_offset: Option<usize>) -> Option<(&str, u32)> {
+        let contains = |start: usize, size: u16, pos: usize| (start..start + size as usize).contains(&pos);
+        // Spans overlap, so every span starting within the largest span size before the position is a candidate
+        let back = self.max_line_size.saturating_sub(1);
+        let by_offset = file_offset.into_iter().flat_map(|pos| {
+            self.lines_by_offset.range(pos.saturating_sub(back as usize)..=pos)
+                .flat_map(|(_, spans)| spans)
+                .filter(move |&&i| contains(self.lines[i].file_offset.unwrap_or_default(), self.lines[i].size, pos))
+        });
+        // Spans without a file offset are matched by address, as are all spans if the address has no file offset
+        let by_addr = self.lines_by_addr.range(addr.saturating_sub(back)..=addr)
+            .flat_map(|(_, spans)| spans)
+            .filter(|&&i| file_offset.is_none() || self.lines[i].file_offset.is_none())
+            .filter(|&&i| contains(self.lines[i].addr as usize, self.lines[i].size, addr as usize));
+        // Prefer the smallest span, as lines inside of macros / .include span less than their callers
+        by_offset.chain(by_addr)
+            .min_by_key(|&&i| (self.lines[i].size, i))
+            .map(|&i| (self.files[self.lines[i].file].as_str(), self.lines[i].line))
+    }
+    fn insert_line(&mut self, span: LineSpan) {
+        let i = self.lines.len();
+        self.lines_by_addr.entry(span.addr).or_default().push(i);
+        if let Some(offset) = span.file_offset { self.lines_by_offset.entry(offset).or_default().push(i); }
+        self.max_line_size = self.max_line_size.max(span.size);
+        self.lines.push(span);
+    }
+
+    /// Parse FCEUX name list: `$C5F5#UpdatePlayer#comment`
+    fn load_fceux_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            let Some(rest) = line.strip_prefix('$') else { continue };
+            let mut fields = rest.split('#');
+            let addr = fields.next().and_then(|a| u16::from_str_radix(a, 16).ok())
+                .ok_or(SymbolError::Malformed { line: i, reason: "invalid address" })?;
+            // Array entries (`$0300/10#buffer#`) only name their first byte
+            let name = fields.next().filter(|n| !n.is_empty());
+            if let Some(name) = name { self.insert(bank, addr, name) }
+        }
+        Ok(())
+    }
+
+    /// Parse VICE label file: `al C:c5f5 .UpdatePlayer`
+    fn load_vice(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            let mut words = line.split_whitespace();
+            if !matches!(words.next(), Some("al" | "add_label")) { continue }
+            let (Some(addr), Some(name)) = (words.next(), words.next()) else {
+                return Err(SymbolError::Malformed { line: i, reason: "expected address and label" })
+            };
+            // Memory space prefix is optional
+            let addr = addr.rsplit(':').next().unwrap_or(addr);
+            let addr = u32::from_str_radix(addr, 16).ok().filter(|a| *a <= 0xFFFF)
+                .ok_or(SymbolError::Malformed { line: i, reason: "invalid address" })?;
+            self.insert(None, addr as u16, name.trim_start_matches('.'));
+        }
+        Ok(())
+    }
+
+    /// Parse ld65 debug info, collecting symbols and the spans of source lines
+    fn load_ld65_dbg(&mut self, text: &str) -> Result<(), SymbolError> {
+        // (cpu start, file offset of start) of each segment
+        let mut segs: HashMap<usize, (u32, Option<usize>)> = HashMap::new();
+        // (segment, start, size) of each span
+        let mut spans: HashMap<usize, (usize, u32, u32)> = HashMap::new();
+        let mut files: HashMap<usize, usize> = HashMap::new();
+        // (file, line, spans) of each line
+        let mut lines = Vec::new();
+        let mut syms = Vec::new();
+
+        for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            let Some((kind, rest)) = line.split_once(char::is_whitespace) else { continue };
+            let attrs = parse_dbg_attrs(rest);
+            let get = |key: &str| attrs.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str());
+            let num = |key: &str| get(key).and_then(parse_dbg_num);
+            let id = || num("id").map(|id| id as usize).ok_or(SymbolError::Malformed { line: i, reason: "missing id" });
+            match kind {
+                "file" => {
+                    files.insert(id()?, self.files.len());
+                    self.files.push(get("name").unwrap_or_default().to_owned());
+                }
+                "seg" => { segs.insert(id()?, (num("start").unwrap_or(0), num("ooffs").map(|o| o as usize))); }
+                "span" => {
+                    let seg = num("seg").ok_or(SymbolError::Malformed { line: i, reason: "span without segment" })?;
+                    spans.insert(id()?, (seg as usize, num("start").unwrap_or(0), num("size").unwrap_or(0)));
+                }
+                "line" => if let (Some(file), Some(line), Some(span)) = (num("file"), num("line"), get("span")) {
+                    let span_ids: Vec<usize> = span.split('+').filter_map(parse_dbg_num).map(|s| s as usize).collect();
+                    lines.push((file as usize, line, span_ids));
+                }
+                "sym" => if let (Some(name), Some(val)) = (get("name"), num("val")) {
+                    // Labels take precedence over constants sharing the same value
+                    syms.push((get("type") != Some("lab"), val, name.to_owned()));
+                }
+                _ => {}
+            }
+        }
+
+        syms.sort_by_key(|(is_equ, _, _)| *is_equ);
+        for (_, val, name) in syms {
+            if val <= 0xFFFF { self.insert(None, val as u16, &name) }
+        }
+        for (file, line, span_ids) in lines {
+            let Some(&file) = files.get(&file) else { continue };
+            for span in span_ids {
+                let Some(&(seg, start, size)) = spans.get(&span) else { continue };
+                let Some(&(seg_start, seg_offset)) = segs.get(&seg) else { continue };
+                let addr = seg_start + start;
+                if addr > 0xFFFF || size == 0 { continue }
+                self.insert_line(LineSpan {
+                    addr: addr as u16,
+                    file_offset: seg_offset.map(|o| o + start as usize),
+                    size: size.min(0xFFFF) as u16,
+                    file,
+                    line,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split `key=value,key="quoted, value"` attribute list of a debug info line
+fn parse_dbg_attrs(text: &str) -> Vec<(&str, String)> {
+    let mut out = Vec::new();
+    let mut rest = text.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let (value, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            (quoted[..end].to_owned(), quoted.get(end + 1..).unwrap_or(""))
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].to_owned(), &after[end..])
+        };
+        out.push((key.trim(), value));
+        rest = remaining.trim_start_matches(',');
+    }
+    out
+}
+/// Parse decimal or `0x` prefixed hex number
+fn parse_dbg_num(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_lines() {
+        let mut symbols = SymbolTable::default();
+        symbols.load_ld65_dbg(r#"
+            file id=0,name="main.s",size=1,mtime=0,mod=0
+            seg id=0,name="CODE",start=0x00C000,size=0x10,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+            seg id=1,name="ZP",start=0x000000,size=0x10,addrsize=zeropage,type=rw
+            span id=0,seg=0,start=0,size=3
+            span id=1,seg=0,start=3,size=8
+            span id=2,seg=0,start=4,size=2
+            span id=3,seg=1,start=2,size=2
+            line id=0,file=0,line=10,span=0
+            line id=1,file=0,line=20,span=1
+            line id=2,file=0,line=5,span=2
+            line id=3,file=0,line=30,span=3
+        "#).unwrap();
+        assert_eq!(symbols.source_line(0xC001, Some(17)), Some(("main.s", 10)));
+        // Inside both line 20 and the smaller line 5
+        assert_eq!(symbols.source_line(0xC005, Some(21)), Some(("main.s", 5)));
+        assert_eq!(symbols.source_line(0xC00A, Some(26)), Some(("main.s", 20)));
+        assert_eq!(symbols.source_line(0xC00B, Some(27)), None);
+        // Without a file offset the address is used
+        assert_eq!(symbols.source_line(0xC004, None), Some(("main.s", 5)));
+        // Same address in another bank
+        assert_eq!(symbols.source_line(0xC001, Some(0x4011)), None);
+        // Segments that aren't in the file always match by address
+        assert_eq!(symbols.source_line(0x0003, Some(0x1234)), Some(("main.s", 30)));
+    }
+}
@@ -0,0 +1,70 @@
+//! Runs test ROMs that report their result in PRG RAM, as blargg's tests do, see: https://www.nesdev.org/wiki/Emulator_tests
+//! $6000 holds the status, $6001-$6003 a signature marking the data as valid and $6004 the zero-terminated result text.
+
+use thiserror::Error;
+
+use crate::State;
+
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT_ADDR: u16 = 0x6004;
+/// Status while the test is running, results are 0 (passed) to $7F
+const RUNNING: u8 = 0x80;
+/// Status asking for the reset button to be pressed
+const NEEDS_RESET: u8 = 0x81;
+/// Seconds to wait before pressing reset, the tests need at least 100ms
+const RESET_DELAY: f64 = 0.1;
+/// CPU cycles run between checks of the status
+const POLL_CYCLES: u64 = 1000;
+
+#[derive(Error, Debug)]
+pub enum TestRomError {
+    #[error("failed with code {code}: {message}")]
+    Failed { code: u8, message: String },
+    #[error("no result after {0} seconds")]
+    Timeout(f64),
+    #[error("CPU stopped before the test finished")]
+    Stopped,
+}
+
+/// Status, or None while the signature hasn't been written yet
+fn status(state: &mut State) -> Option<u8> {
+    let cart = &mut state.mem.cartridge;
+    let signature = [0, 1, 2].map(|i| cart.read(SIGNATURE_ADDR + i).unwrap_or(0));
+    if signature != SIGNATURE { return None }
+    cart.read(STATUS_ADDR)
+}
+
+/// Result text printed by the test
+fn text(state: &mut State) -> String {
+    let cart = &mut state.mem.cartridge;
+    let bytes: Vec<u8> = (TEXT_ADDR..0x8000).map_while(|addr| cart.read(addr).filter(|&byte| byte != 0)).collect();
+    String::from_utf8_lossy(&bytes).trim().to_owned()
+}
+
+/// Run a loaded and reset test ROM until it reports a result, pressing reset when it asks for it.
+/// Returns the result text if the test passed.
+pub fn run(state: &mut State, timeout: f64) -> Result<String, TestRomError> {
+    let master_clock = state.region.master_clock();
+    let end = state.mem.scheduler.now() + (timeout * master_clock) as u64;
+    let poll = POLL_CYCLES * state.region.cpu_divider() as u64;
+    // The status stays at NEEDS_RESET until the restarted test overwrites it
+    let mut reset_pressed = false;
+    while state.mem.scheduler.now() < end {
+        if !state.run_until(state.mem.scheduler.now() + poll) { return Err(TestRomError::Stopped) }
+        match status(state) {
+            Some(NEEDS_RESET) if reset_pressed => {}
+            None | Some(RUNNING) => reset_pressed = false,
+            Some(NEEDS_RESET) => {
+                let delay = state.mem.scheduler.now() + (RESET_DELAY * master_clock) as u64;
+                if !state.run_until(delay) { return Err(TestRomError::Stopped) }
+                state.soft_reset();
+                reset_pressed = true;
+            }
+            Some(0) => return Ok(text(state)),
+            Some(code) => return Err(TestRomError::Failed { code, message: text(state) }),
+        }
+    }
+    Err(TestRomError::Timeout(timeout))
+}
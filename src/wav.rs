@@ -0,0 +1,27 @@
+//! Minimal writer for 16-bit mono PCM WAV files: http://soundfile.sapp.org/doc/WaveFormat/
+
+use std::{fs::File, io::{self, BufWriter, Write}, path::Path};
+
+pub fn write_wav(path: &Path, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let data_len = samples.len() as u32 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?; // Size of fmt chunk
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // Mono
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?; // Byte rate
+    out.write_all(&2u16.to_le_bytes())?; // Block align
+    out.write_all(&16u16.to_le_bytes())?; // Bits per sample
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    out.flush()
+}
@@ -0,0 +1,92 @@
+//! Frame counter, length counter and $4015 status tests in the style of blargg's `apu_test` and `apu_reset`.
+//! The ROMs are assembled here so they run without the nes-test-roms checkout, see `tests/blargg_apu.rs`.
+//! Each check jumps to `fail` with its number as the result code.
+
+mod common;
+
+use common::*;
+
+fn run(name: &str, body: impl FnOnce(&mut Asm)) {
+    if let Err(output) = run_built_rom(name, &test_rom(body)) {
+        panic!("{name}: {output}");
+    }
+}
+
+#[test]
+fn len_ctr() {
+    run("len_ctr.nes", |a| {
+        // Pulse 1 enabled, length counter running, length index 3 loads 2
+        a.store(0x4015, 0x01).store(0x4000, 0x10).store(0x4003, 0x18);
+        a.expect_status(0x01, 0x01, 2);
+        // Switching to the 5-step sequence clocks a half frame once the write takes effect
+        a.store(0x4017, 0x80).delay(30).expect_status(0x01, 0x01, 3);
+        a.store(0x4017, 0x80).delay(30).expect_status(0x01, 0x00, 4);
+        // Disabling the channel clears the length counter, and loading it while disabled does nothing
+        a.store(0x4003, 0x18).expect_status(0x01, 0x01, 5);
+        a.store(0x4015, 0x00).expect_status(0x01, 0x00, 6);
+        a.store(0x4003, 0x18).expect_status(0x01, 0x00, 7);
+        // Halted length counters don't count
+        a.store(0x4015, 0x01).store(0x4000, 0x30).store(0x4003, 0x18);
+        a.store(0x4017, 0x80).delay(30).store(0x4017, 0x80).delay(30).expect_status(0x01, 0x01, 8);
+        a.jump(JMP_ABS, "pass");
+    });
+}
+
+#[test]
+fn irq_flag() {
+    run("irq_flag.nes", |a| {
+        // The 4-step sequence sets the flag, reading $4015 clears it
+        a.store(0x4017, 0x40).store(0x4017, 0x00).delay(30000).expect_status(0x40, 0x40, 2);
+        a.expect_status(0x40, 0x00, 3);
+        // Setting the inhibit flag clears it, and keeps it clear
+        a.store(0x4017, 0x00).delay(30000).store(0x4017, 0x40).expect_status(0x40, 0x00, 4);
+        a.delay(30000).expect_status(0x40, 0x00, 5);
+        // The 5-step sequence never sets it
+        a.store(0x4017, 0x80).delay(40000).expect_status(0x40, 0x00, 6);
+        // With interrupts enabled the flag raises an IRQ, the handler counts it
+        a.store(0x0010, 0).store(0x4017, 0x00).op(CLI).delay(30000).op(SEI);
+        a.abs(LDA_ABS, 0x0010).imm(CMP_IMM, 1).fail_unless_equal(7);
+        a.jump(JMP_ABS, "pass");
+    });
+}
+
+#[test]
+fn irq_flag_timing() {
+    run("irq_flag_timing.nes", |a| {
+        // The flag is set 29830 cycles after the write to $4017, give or take the write delay.
+        // The reads land about 80 cycles before and after.
+        a.store(0x4017, 0x40).store(0x4017, 0x00);
+        a.delay(29750).expect_status(0x40, 0x00, 2);
+        a.delay(150).expect_status(0x40, 0x40, 3);
+        a.jump(JMP_ABS, "pass");
+    });
+}
+
+#[test]
+fn apu_reset() {
+    run("apu_reset.nes", |a| {
+        // $0300 counts the resets, RAM is kept through them
+        a.abs(LDA_ABS, 0x0300).imm(CMP_IMM, 1).imm(BNE, 3).jump(JMP_ABS, "after_first");
+        a.imm(CMP_IMM, 2).imm(BNE, 3).jump(JMP_ABS, "after_second");
+
+        // Enable every channel with a long length, and let the frame IRQ flag get set without reading it
+        a.store(0x4015, 0x0F);
+        for reg in [0x4003, 0x4007, 0x400B, 0x400F] {
+            a.store(reg, 0xF8);
+        }
+        a.store(0x4017, 0x00).delay(30000);
+        a.store(0x0300, 1).jump(JMP_ABS, "reset");
+
+        // Reset disables the channels and clears the frame IRQ flag
+        a.label("after_first").abs(LDA_ABS, 0x4015).abs(STA_ABS, 0x0301);
+        a.imm(AND_IMM, 0x1F).imm(CMP_IMM, 0).fail_unless_equal(2);
+        a.abs(LDA_ABS, 0x0301).imm(AND_IMM, 0x40).imm(CMP_IMM, 0).fail_unless_equal(3);
+        // The frame counter runs right away, in the 4-step mode written last
+        a.delay(30000).expect_status(0x40, 0x40, 4);
+        a.store(0x4017, 0x80).store(0x0300, 2).jump(JMP_ABS, "reset");
+
+        // Reset writes the last $4017 value again, so the 5-step mode is kept and the flag never set
+        a.label("after_second").delay(40000).expect_status(0x40, 0x00, 5);
+        a.jump(JMP_ABS, "pass");
+    });
+}
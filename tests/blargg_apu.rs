@@ -0,0 +1,66 @@
+//! blargg's APU test ROMs, run through the `test-rom` subcommand.
+//! The ROMs come from the nes-test-roms collection, checked out at `tests/test-roms` (see `.gitmodules`).
+//! Fetch it and run them with:
+//!
+//! ```sh
+//! git clone https://github.com/christopherpow/nes-test-roms tests/test-roms
+//! cargo test --test blargg_apu -- --ignored
+//! ```
+//!
+//! A missing ROM fails the test instead of being skipped.
+//! `tests/apu_frame_counter.rs` covers the same ground with ROMs assembled in the tests, and always runs.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+const APU_TEST: &[&str] = &[
+    "1-len_ctr.nes",
+    "2-len_table.nes",
+    "3-irq_flag.nes",
+    "4-jitter.nes",
+    "5-len_timing.nes",
+    "6-irq_flag_timing.nes",
+    "7-dmc_basics.nes",
+    "8-dmc_rates.nes",
+];
+const APU_RESET: &[&str] = &[
+    "4015_cleared.nes",
+    "4017_timing.nes",
+    "4017_written.nes",
+    "irq_flag_cleared.nes",
+    "len_ctrs_enabled.nes",
+    "works_immediately.nes",
+];
+
+fn rom_dir(dir: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/test-roms").join(dir)
+}
+
+/// Run each ROM and collect the failures, so one failing ROM doesn't hide the others
+fn run_roms(dir: &Path, roms: &[&str]) {
+    let mut failures = Vec::new();
+    for rom in roms {
+        let path = dir.join(rom);
+        if !path.exists() {
+            failures.push(format!("{rom}: not found at {}, clone nes-test-roms into tests/test-roms", path.display()));
+            continue
+        }
+        if let Err(output) = common::run_test_rom(&path) {
+            failures.push(format!("{rom}: {output}"));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+#[ignore = "needs the ROMs in tests/test-roms"]
+fn apu_test() {
+    run_roms(&rom_dir("apu_test/rom_singles"), APU_TEST);
+}
+
+#[test]
+#[ignore = "needs the ROMs in tests/test-roms"]
+fn apu_reset() {
+    run_roms(&rom_dir("apu_reset"), APU_RESET);
+}
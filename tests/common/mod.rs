@@ -0,0 +1,169 @@
+//! Test ROMs assembled in the tests, and a runner for ROMs that report their result at $6000 like blargg's do.
+
+#![allow(dead_code)]
+
+use std::{collections::HashMap, path::Path, process::Command};
+
+pub const LDA_IMM: u8 = 0xA9;
+pub const LDA_ABS: u8 = 0xAD;
+pub const LDX_IMM: u8 = 0xA2;
+pub const LDY_IMM: u8 = 0xA0;
+pub const STA_ABS: u8 = 0x8D;
+pub const STY_ABS: u8 = 0x8C;
+pub const AND_IMM: u8 = 0x29;
+pub const CMP_IMM: u8 = 0xC9;
+pub const INC_ABS: u8 = 0xEE;
+pub const BEQ: u8 = 0xF0;
+pub const BNE: u8 = 0xD0;
+pub const JMP_ABS: u8 = 0x4C;
+pub const JSR: u8 = 0x20;
+pub const RTS: u8 = 0x60;
+pub const RTI: u8 = 0x40;
+pub const PHA: u8 = 0x48;
+pub const PLA: u8 = 0x68;
+pub const SEI: u8 = 0x78;
+pub const CLI: u8 = 0x58;
+pub const CLD: u8 = 0xD8;
+pub const TXS: u8 = 0x9A;
+pub const DEX: u8 = 0xCA;
+pub const DEY: u8 = 0x88;
+pub const NOP: u8 = 0xEA;
+
+/// Result status and signature of the test ROM protocol, see `src/test_rom.rs`
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+
+/// Tiny 6502 assembler: instructions are given as opcode and operand, branches and jumps go to named labels
+pub struct Asm {
+    org: u16,
+    code: Vec<u8>,
+    labels: HashMap<&'static str, u16>,
+    /// Operands to fill in once all labels are known: position, label, relative (branch) or absolute
+    fixups: Vec<(usize, &'static str, bool)>,
+}
+impl Asm {
+    pub fn new(org: u16) -> Self {
+        Self { org, code: Vec::new(), labels: HashMap::new(), fixups: Vec::new() }
+    }
+    fn pc(&self) -> u16 { self.org + self.code.len() as u16 }
+    pub fn label(&mut self, name: &'static str) -> &mut Self {
+        assert!(self.labels.insert(name, self.pc()).is_none(), "label {name} defined twice");
+        self
+    }
+    /// Implied addressing
+    pub fn op(&mut self, opcode: u8) -> &mut Self {
+        self.code.push(opcode);
+        self
+    }
+    pub fn imm(&mut self, opcode: u8, val: u8) -> &mut Self {
+        self.code.extend([opcode, val]);
+        self
+    }
+    pub fn abs(&mut self, opcode: u8, addr: u16) -> &mut Self {
+        self.code.push(opcode);
+        self.code.extend(addr.to_le_bytes());
+        self
+    }
+    pub fn branch(&mut self, opcode: u8, label: &'static str) -> &mut Self {
+        self.fixups.push((self.code.len() + 1, label, true));
+        self.code.extend([opcode, 0]);
+        self
+    }
+    /// JMP or JSR to a label
+    pub fn jump(&mut self, opcode: u8, label: &'static str) -> &mut Self {
+        self.fixups.push((self.code.len() + 1, label, false));
+        self.code.extend([opcode, 0, 0]);
+        self
+    }
+    /// Store a value with LDA / STA
+    pub fn store(&mut self, addr: u16, val: u8) -> &mut Self {
+        self.imm(LDA_IMM, val).abs(STA_ABS, addr)
+    }
+    /// After a compare, jump to `fail` with `code` unless the values were equal
+    pub fn fail_unless_equal(&mut self, code: u8) -> &mut Self {
+        // BEQ over LDY and JMP, `fail` may be out of branch range
+        self.imm(BEQ, 5).imm(LDY_IMM, code).jump(JMP_ABS, "fail")
+    }
+    /// Read $4015 (acknowledging the frame IRQ) and jump to `fail` with `code` unless the bits in `mask` read as `expected`
+    pub fn expect_status(&mut self, mask: u8, expected: u8, code: u8) -> &mut Self {
+        self.abs(LDA_ABS, 0x4015).imm(AND_IMM, mask).imm(CMP_IMM, expected).fail_unless_equal(code)
+    }
+    /// Busy wait for `cycles` CPU cycles (at least 24) using the `delay` routine of `test_rom`, accurate to 10 cycles
+    pub fn delay(&mut self, cycles: u32) -> &mut Self {
+        // LDY, LDX, JSR, the first inner loop (5 cycles a pass), DEY, the final BNE and RTS take 19 + 5 * inner cycles,
+        // every further outer loop takes 1284
+        let outer = 1 + (cycles - 24) / 1284;
+        let inner = ((cycles - 19 - (outer - 1) * 1284) / 5).min(256);
+        assert!(outer < 256);
+        // X=0 runs the inner loop 256 times
+        self.imm(LDY_IMM, outer as u8).imm(LDX_IMM, inner as u8).jump(JSR, "delay")
+    }
+    /// Machine code with all labels resolved
+    pub fn finish(mut self) -> Vec<u8> {
+        for (pos, label, relative) in std::mem::take(&mut self.fixups) {
+            let target = *self.labels.get(label).unwrap_or_else(|| panic!("undefined label {label}"));
+            if relative {
+                let offset = target as i32 - (self.org as i32 + pos as i32 + 1);
+                self.code[pos] = i8::try_from(offset).unwrap_or_else(|_| panic!("branch to {label} out of range")) as u8;
+            } else {
+                self.code[pos..pos + 2].copy_from_slice(&target.to_le_bytes());
+            }
+        }
+        self.code
+    }
+}
+
+/// Build a 16KB NROM test ROM at $C000 around `body`, which runs after the result signature is written.
+/// `body` ends by jumping to `pass`, or to `fail` with the failure code in Y. It can also jump to `reset`, which asks
+/// for the reset button and then starts over at the reset vector with RAM kept. The IRQ handler acknowledges the frame
+/// IRQ and counts interrupts at $0010.
+pub fn test_rom(body: impl FnOnce(&mut Asm)) -> Vec<u8> {
+    let mut a = Asm::new(0xC000);
+    // Outer loop runs Y times, the inner loop X times, then X=0 so later outer loops run it 256 times, see `Asm::delay`
+    a.label("delay").label("delay_inner").op(DEX).branch(BNE, "delay_inner").op(DEY).branch(BNE, "delay_inner").op(RTS);
+    a.label("irq").op(PHA).abs(LDA_ABS, 0x4015).abs(INC_ABS, 0x0010).op(PLA).label("nmi").op(RTI);
+
+    a.label("start").op(SEI).op(CLD).imm(LDX_IMM, 0xFF).op(TXS);
+    for (i, byte) in SIGNATURE.into_iter().enumerate() {
+        a.store(STATUS + 1 + i as u16, byte);
+    }
+    a.store(STATUS, RUNNING);
+    body(&mut a);
+
+    a.label("pass").imm(LDY_IMM, 0);
+    a.label("fail").abs(STY_ABS, STATUS).label("done").jump(JMP_ABS, "done");
+    a.label("reset").store(STATUS, NEEDS_RESET).label("wait_reset").jump(JMP_ABS, "wait_reset");
+    let labels = (a.labels["nmi"], a.labels["start"], a.labels["irq"]);
+    let code = a.finish();
+
+    let mut prg = vec![0xFF; 0x4000];
+    prg[..code.len()].copy_from_slice(&code);
+    for (i, vector) in [labels.0, labels.1, labels.2].into_iter().enumerate() {
+        prg[0x3FFA + i * 2..][..2].copy_from_slice(&vector.to_le_bytes());
+    }
+    let mut rom = b"NES\x1a\x01\x00\x00\x00".to_vec();
+    rom.resize(16, 0);
+    rom.extend(prg);
+    rom
+}
+
+/// Run a test ROM with the `test-rom` subcommand, returns its output as the error if it fails
+pub fn run_test_rom(path: &Path) -> Result<(), String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_em6502"))
+        .arg("test-rom")
+        .arg(path)
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .expect("failed to run emulator");
+    if output.status.success() { return Ok(()) }
+    Err(format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)))
+}
+
+/// Write an assembled test ROM to the test directory and run it
+pub fn run_built_rom(name: &str, rom: &[u8]) -> Result<(), String> {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, rom).unwrap();
+    run_test_rom(&path)
+}